@@ -0,0 +1,55 @@
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use uuid::Uuid;
+
+use rusty_chat::model::feed::Feed;
+use rusty_chat::model::message::Message;
+use rusty_chat::model::user::User;
+
+const HISTORY_LENGTHS: [usize; 3] = [1_000, 100_000, 1_000_000];
+
+// Full feed, so that every post also drops the oldest message
+fn full_feed(user: &User, length: usize) -> (Feed, Vec<Uuid>) {
+    let mut feed = Feed::new(length);
+    let ids: Vec<Uuid> = (0..length).map(|_| Uuid::new_v4()).collect();
+    for id in &ids {
+        feed.add_message(Message::new(*id, user.clone(), "Hello", Utc::now()));
+    }
+    (feed, ids)
+}
+
+fn post(c: &mut Criterion) {
+    let user = User::new(Uuid::new_v4(), "John");
+    let mut group = c.benchmark_group("post");
+    for length in &HISTORY_LENGTHS {
+        let (mut feed, _) = full_feed(&user, *length);
+        group.bench_with_input(BenchmarkId::from_parameter(length), length, |b, _| {
+            b.iter(|| {
+                feed.add_message(Message::new(
+                    Uuid::new_v4(),
+                    user.clone(),
+                    "Hello",
+                    Utc::now(),
+                ))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn history(c: &mut Criterion) {
+    let user = User::new(Uuid::new_v4(), "John");
+    let mut group = c.benchmark_group("history");
+    for length in &HISTORY_LENGTHS {
+        let (feed, ids) = full_feed(&user, *length);
+        // Page from the middle of the feed
+        let before = ids[length / 2];
+        group.bench_with_input(BenchmarkId::from_parameter(length), length, |b, _| {
+            b.iter(|| feed.messages_before(Some(before), 50).count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, post, history);
+criterion_main!(benches);
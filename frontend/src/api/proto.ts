@@ -4,8 +4,8 @@ function join(name: string): JoinInput {
     return { type: InputType.Join, payload: { name } };
 }
 
-function post(body: string): PostInput {
-    return { type: InputType.Post, payload: { body } };
+function post(roomId: string, body: string): PostInput {
+    return { type: InputType.Post, payload: { roomId, body } };
 }
 
 const apiProto = {
@@ -27,7 +27,7 @@ export type JoinInput = {
 
 export type PostInput = {
     type: InputType.Post;
-    payload: { body: string; };
+    payload: { roomId: string; body: string; };
 };
 
 export type Input = JoinInput | PostInput;
@@ -59,6 +59,11 @@ export type UserOutput = {
     name: string;
 };
 
+export type RoomOutput = {
+    id: string;
+    name: string;
+};
+
 export type MessageOutput = {
     id: string;
     user: UserOutput;
@@ -78,6 +83,7 @@ export type AliveOutput = {
 export type JoinedOutput = {
     type: OutputType.Joined;
     payload: {
+        room: RoomOutput;
         user: UserOutput;
         others: UserOutput[];
         messages: MessageOutput[];
@@ -87,6 +93,7 @@ export type JoinedOutput = {
 export type UserJoinedOutput = {
     type: OutputType.UserJoined;
     payload: {
+        roomId: string;
         user: UserOutput;
     };
 };
@@ -94,6 +101,7 @@ export type UserJoinedOutput = {
 export type UserLeftOutput = {
     type: OutputType.UserLeft;
     payload: {
+        roomId: string;
         userId: string;
     };
 };
@@ -101,6 +109,7 @@ export type UserLeftOutput = {
 export type PostedOutput = {
     type: OutputType.Posted;
     payload: {
+        roomId: string;
         message: MessageOutput;
     };
 };
@@ -108,6 +117,7 @@ export type PostedOutput = {
 export type UserPostedOutput = {
     type: OutputType.UserPosted;
     payload: {
+        roomId: string;
         message: MessageOutput;
     };
 };
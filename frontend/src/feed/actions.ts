@@ -11,8 +11,8 @@ import {
     UserLeftFeedAction,
 } from './types';
 
-function load(messages: MessageData[], users: UserData[]): LoadFeedAction {
-    return { type: FeedActionType.Load, payload: { messages, users } };
+function load(roomId: string, messages: MessageData[], users: UserData[]): LoadFeedAction {
+    return { type: FeedActionType.Load, payload: { roomId, messages, users } };
 }
 
 function post(body: string): PostFeedAction {
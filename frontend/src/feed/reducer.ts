@@ -1,6 +1,7 @@
 import { FeedAction, FeedActionType, FeedState } from './types';
 
 const initialState: FeedState = {
+    roomId: null,
     postError: null,
     messages: [],
     users: [],
@@ -14,6 +15,7 @@ export default function feedReducer(
         case FeedActionType.Load:
             return {
                 ...state,
+                roomId: action.payload.roomId,
                 users: action.payload.users,
                 messages: action.payload.messages
                     .sort((a, b) => b.createdAt.getTime() - a.createdAt.getTime()),
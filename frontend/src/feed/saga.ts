@@ -1,7 +1,8 @@
-import { put, StrictEffect, take, takeEvery } from '@redux-saga/core/effects';
+import { put, select, StrictEffect, take, takeEvery } from '@redux-saga/core/effects';
 import apiActions from '../api/actions';
 import apiProto from '../api/proto';
 import { ApiActionType, OutputType, ReadApiAction } from '../api/types';
+import { AppState } from '../store';
 import { JoinedUserAction, UserActionType } from '../user/types';
 import feedActions from './actions';
 import { FeedActionType, PostFeedAction } from './types';
@@ -12,12 +13,13 @@ function* handleUserJoined(action: JoinedUserAction): Generator<StrictEffect> {
             ...message,
             createdAt: new Date(message.createdAt),
         }));
-        yield put(feedActions.load(messages, action.payload.others));
+        yield put(feedActions.load(action.payload.roomId, messages, action.payload.others));
     }
 }
 
 function* handlePost(action: PostFeedAction): Generator<StrictEffect> {
-    yield put(apiActions.write(apiProto.post(action.payload.body)));
+    const roomId = (yield select((state: AppState) => state.feed.roomId)) as string;
+    yield put(apiActions.write(apiProto.post(roomId, action.payload.body)));
 
     while (true) {
         const read = (yield take(ApiActionType.Read)) as ReadApiAction;
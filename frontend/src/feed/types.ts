@@ -9,6 +9,7 @@ export type MessageData = {
 };
 
 export type FeedState = {
+    roomId: string | null;
     messages: MessageData[];
     users: UserData[];
     postError: OutputError | null;
@@ -25,6 +26,7 @@ export enum FeedActionType {
 export type LoadFeedAction = {
     type: FeedActionType.Load;
     payload: {
+        roomId: string;
         messages: MessageData[];
         users: UserData[];
     };
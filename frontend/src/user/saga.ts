@@ -19,6 +19,7 @@ function* handleJoin(action: JoinUserAction): Generator<StrictEffect> {
             const output = read.payload.payload;
             yield put(userActions.joined({
                 error: false,
+                roomId: output.room.id,
                 user: output.user,
                 others: output.others,
                 messages: output.messages,
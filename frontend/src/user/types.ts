@@ -27,6 +27,7 @@ export type JoinedUserAction = {
 };
 
 export type JoinedUserActionOk = {
+    roomId: string;
     user: UserData;
     others: UserData[];
     messages: MessageData[];
@@ -0,0 +1,620 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+use warp::http::{HeaderValue, StatusCode};
+use warp::reply::{self, Response};
+use warp::{Filter, Rejection, Reply};
+
+use crate::hub::Hub;
+use crate::proto::{
+    FetchHistoryInput, HelloInput, HistoryOutput, Input, InputParcel, JoinInput, JoinRoomInput,
+    Output, OutputError, PostInput, PostedOutput, PROTOCOL_VERSION,
+};
+
+const DEFAULT_PAGE_LENGTH: usize = 50;
+
+/// Who may post over HTTP, and under which name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiOptions {
+    /// Bearer token that callers of `POST /messages` must present.
+    pub token: String,
+    /// Name the service joins the hub with.
+    pub user_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessagesQuery {
+    room_id: Option<Uuid>,
+    before: Option<Uuid>,
+    limit: Option<usize>,
+}
+
+struct Connection {
+    client_id: Uuid,
+    replies: UnboundedReceiver<Output>,
+    forwarding: JoinHandle<()>,
+}
+
+/// Hub client that posts on behalf of HTTP callers, so that their messages
+/// are broadcast like any other post.
+pub struct ServiceClient {
+    options: ApiOptions,
+    hub: Arc<Hub>,
+    input_sender: UnboundedSender<InputParcel>,
+    // One request at a time, since errors carry nothing to tell which input
+    // they answer
+    connection: Mutex<Option<Connection>>,
+}
+
+impl ServiceClient {
+    pub fn new(
+        options: ApiOptions,
+        hub: Arc<Hub>,
+        input_sender: UnboundedSender<InputParcel>,
+    ) -> Self {
+        ServiceClient {
+            options,
+            hub,
+            input_sender,
+            connection: Mutex::new(None),
+        }
+    }
+
+    pub fn authorize(&self, authorization: Option<&str>) -> bool {
+        let token = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(token) => token,
+            None => return false,
+        };
+        // Compare every byte, so that timing does not give the token away
+        token.len() == self.options.token.len()
+            && token
+                .bytes()
+                .zip(self.options.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    pub async fn post(self: &Arc<Self>, mut input: PostInput) -> Result<PostedOutput, OutputError> {
+        // Tell the reply to this post apart by its nonce
+        let nonce = input
+            .nonce
+            .get_or_insert_with(|| Uuid::new_v4().to_simple().to_string())
+            .clone();
+        let room_id = input.room_id;
+        let output = self
+            .exchange(room_id, Input::Post(input), move |output| {
+                matches!(output, Output::Posted(posted) if posted.nonce.as_ref() == Some(&nonce))
+            })
+            .await?;
+        match output {
+            Output::Posted(output) => Ok(output),
+            _ => Err(OutputError::Internal),
+        }
+    }
+
+    /// Reads a page of a room's history as the service, which has to be a
+    /// member of the room like anyone else.
+    pub async fn history(
+        self: &Arc<Self>,
+        input: FetchHistoryInput,
+    ) -> Result<HistoryOutput, OutputError> {
+        let room_id = input.room_id;
+        let output = self
+            .exchange(room_id, Input::FetchHistory(input), move |output| {
+                matches!(output, Output::History(history) if history.room_id == room_id)
+            })
+            .await?;
+        match output {
+            Output::History(output) => Ok(output),
+            _ => Err(OutputError::Internal),
+        }
+    }
+
+    async fn exchange(
+        self: &Arc<Self>,
+        room_id: Uuid,
+        input: Input,
+        is_reply: impl Fn(&Output) -> bool + Send + Sync + 'static,
+    ) -> Result<Output, OutputError> {
+        // See the exchange through even if the caller goes away, so that no
+        // reply is left behind for the next request
+        let service = self.clone();
+        tokio::spawn(async move {
+            let mut connection = service.connection.lock().await;
+            // Join lazily, and again once the hub dropped the previous connection
+            if !connection.as_mut().is_some_and(Connection::clear) {
+                *connection = Some(service.join().await?);
+            }
+            let connection = connection.as_mut().unwrap();
+
+            match service
+                .request(connection, input.clone(), &is_reply)
+                .await
+            {
+                // Enter rooms on the first request about them
+                Err(OutputError::NotInRoom) => {
+                    service
+                        .request(
+                            connection,
+                            Input::JoinRoom(JoinRoomInput { room_id }),
+                            |output| matches!(output, Output::Joined(joined) if joined.room.id == room_id),
+                        )
+                        .await?;
+                    service.request(connection, input, &is_reply).await
+                }
+                result => result,
+            }
+        })
+        .await
+        .unwrap_or(Err(OutputError::Internal))
+    }
+
+    async fn join(&self) -> Result<Connection, OutputError> {
+        let client_id = Uuid::new_v4();
+        let outbox = self.hub.connect_service(client_id);
+        let (reply_sender, replies) = mpsc::unbounded_channel();
+        // Keep replies to the service's own inputs and drop everything broadcast
+        let forwarding = tokio::spawn(async move {
+            while let Some(output) = outbox.recv().await {
+                if let Output::Hello(_)
+                | Output::Joined(_)
+                | Output::Posted(_)
+                | Output::History(_)
+                | Output::Error(_) = output
+                {
+                    if reply_sender.send(output).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        let mut connection = Connection {
+            client_id,
+            replies,
+            forwarding,
+        };
+
+        // Speak the newest version, so that posts go to the room they name
+        let hello = Input::Hello(HelloInput {
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        });
+        let user_name = &self.options.user_name;
+        let join = Input::Join(JoinInput {
+            name: user_name.clone(),
+            credential: None,
+        });
+        let joined = match self
+            .request(&mut connection, hello, |output| {
+                matches!(output, Output::Hello(_))
+            })
+            .await
+        {
+            Ok(_) => {
+                self.request(&mut connection, join, |output| {
+                    matches!(output, Output::Joined(joined) if &joined.user.name == user_name)
+                })
+                .await
+            }
+            Err(error) => Err(error),
+        };
+        if let Err(error) = joined {
+            self.hub.on_disconnect(client_id, None).await;
+            return Err(error);
+        }
+        Ok(connection)
+    }
+
+    async fn request(
+        &self,
+        connection: &mut Connection,
+        input: Input,
+        is_reply: impl Fn(&Output) -> bool,
+    ) -> Result<Output, OutputError> {
+        self.input_sender
+            .send(InputParcel::new(connection.client_id, input))
+            .map_err(|_| OutputError::Internal)?;
+        loop {
+            match connection.replies.recv().await {
+                Some(Output::Error(error)) => return Err(error),
+                Some(output) if is_reply(&output) => return Ok(output),
+                // Meant for an earlier request
+                Some(_) => continue,
+                // Kicked while waiting
+                None => return Err(OutputError::Internal),
+            }
+        }
+    }
+}
+
+impl Connection {
+    // Drops replies nobody waits for, such as the rest of a failed join,
+    // returning whether the hub still holds the connection open
+    fn clear(&mut self) -> bool {
+        loop {
+            match self.replies.try_recv() {
+                Ok(_) => continue,
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.forwarding.abort();
+    }
+}
+
+/// JSON endpoints for reading the chat and posting to it without a
+/// WebSocket.
+pub fn routes(
+    hub: Arc<Hub>,
+    service: Option<Arc<ServiceClient>>,
+    max_body_size: u64,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+    let service = warp::any().map(move || service.clone());
+
+    let list_messages = warp::path!("messages")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<MessagesQuery>())
+        .and(hub.clone())
+        .and(service.clone())
+        .then(list_messages);
+
+    let list_users = warp::path!("users")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(hub)
+        .and(service.clone())
+        .then(list_users);
+
+    let post_message = warp::path!("messages")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(max_body_size))
+        .and(warp::body::json())
+        .and(service)
+        .then(post_message);
+
+    list_messages
+        .or(list_users)
+        .unify()
+        .or(post_message)
+        .unify()
+}
+
+async fn list_messages(
+    authorization: Option<String>,
+    query: MessagesQuery,
+    hub: Arc<Hub>,
+    service: Option<Arc<ServiceClient>>,
+) -> Response {
+    let room_id = query.room_id.unwrap_or_else(|| hub.default_room_id());
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LENGTH);
+    // Where users have to prove who they are, so do readers, and they only
+    // see rooms the service is a member of
+    if hub.requires_authentication() {
+        let service = match authorize(service, authorization) {
+            Some(service) => service,
+            None => return unauthorized_reply(),
+        };
+        let input = FetchHistoryInput {
+            room_id,
+            before: query.before,
+            limit,
+        };
+        return match service.history(input).await {
+            Ok(history) => reply::json(&history).into_response(),
+            Err(error) => error_reply(error),
+        };
+    }
+    match hub.history(room_id, query.before, limit).await {
+        Some(history) => reply::json(&history).into_response(),
+        None => error_reply(OutputError::RoomNotFound),
+    }
+}
+
+async fn list_users(
+    authorization: Option<String>,
+    hub: Arc<Hub>,
+    service: Option<Arc<ServiceClient>>,
+) -> Response {
+    if hub.requires_authentication() && authorize(service, authorization).is_none() {
+        return unauthorized_reply();
+    }
+    reply::json(&hub.users().await).into_response()
+}
+
+async fn post_message(
+    authorization: Option<String>,
+    input: PostInput,
+    service: Option<Arc<ServiceClient>>,
+) -> Response {
+    let service = match authorize(service, authorization) {
+        Some(service) => service,
+        None => return unauthorized_reply(),
+    };
+    match service.post(input).await {
+        Ok(posted) => reply::with_status(reply::json(&posted), StatusCode::CREATED).into_response(),
+        Err(error) => error_reply(error),
+    }
+}
+
+// Closed to everyone unless a token is configured
+fn authorize(
+    service: Option<Arc<ServiceClient>>,
+    authorization: Option<String>,
+) -> Option<Arc<ServiceClient>> {
+    service.filter(|service| service.authorize(authorization.as_deref()))
+}
+
+fn unauthorized_reply() -> Response {
+    let mut response = error_reply(OutputError::Unauthorized);
+    response
+        .headers_mut()
+        .insert("www-authenticate", HeaderValue::from_static("Bearer"));
+    response
+}
+
+fn error_reply(error: OutputError) -> Response {
+    let status = match error {
+        OutputError::InvalidMessageBody | OutputError::MalformedInput => StatusCode::BAD_REQUEST,
+        OutputError::Unauthorized => StatusCode::UNAUTHORIZED,
+        OutputError::Muted | OutputError::Banned => StatusCode::FORBIDDEN,
+        OutputError::RoomNotFound | OutputError::MessageNotFound => StatusCode::NOT_FOUND,
+        OutputError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let mut response = reply::with_status(reply::json(&error), status).into_response();
+    if let OutputError::RateLimited { retry_after_ms } = error {
+        // Whole seconds, rounded up so that a retry is never early
+        let retry_after_secs = retry_after_ms.div_ceil(1000);
+        response
+            .headers_mut()
+            .insert("retry-after", retry_after_secs.into());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::runtime::Runtime;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+    use warp::http::StatusCode;
+
+    use crate::api::{self, ApiOptions, ServiceClient};
+    use crate::auth::TokenAuthenticator;
+    use crate::hub::{Hub, HubOptions};
+    use crate::proto::{
+        HistoryOutput, Input, InputParcel, JoinInput, Output, OutputError, PostedOutput, UserOutput,
+    };
+
+    #[test]
+    fn post_and_read() {
+        let hub = Arc::new(Hub::new(HubOptions::default()));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let options = ApiOptions {
+            token: String::from("secret"),
+            user_name: String::from("Service"),
+        };
+        let service = Arc::new(ServiceClient::new(options, hub.clone(), sender.clone()));
+        let routes = api::routes(hub.clone(), Some(service), 1024);
+        let room_id = hub.default_room_id();
+
+        Runtime::new().unwrap().block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let john = hub.connect(john_id, None);
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(john.recv().await, Some(Output::Joined(_))));
+
+                // Posting needs the token
+                let body = format!(r#"{{"roomId":"{}","body":"Deployed"}}"#, room_id);
+                for authorization in &["", "Bearer wrong", "secret"] {
+                    let response = warp::test::request()
+                        .method("POST")
+                        .path("/messages")
+                        .header("authorization", *authorization)
+                        .body(&body)
+                        .reply(&routes)
+                        .await;
+                    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                }
+                let response = warp::test::request()
+                    .method("POST")
+                    .path("/messages")
+                    .header("authorization", "Bearer secret")
+                    .body(&body)
+                    .reply(&routes)
+                    .await;
+                assert_eq!(response.status(), StatusCode::CREATED);
+                let posted: PostedOutput = serde_json::from_slice(response.body()).unwrap();
+                assert_eq!(posted.message.user.name, "Service");
+                assert_eq!(posted.message.body, "Deployed");
+
+                // Others see the service join and post like any user
+                match john.recv().await {
+                    Some(Output::UserJoined(output)) => assert_eq!(output.user.name, "Service"),
+                    output => panic!("unexpected output: {:?}", output),
+                }
+                match john.recv().await {
+                    Some(Output::UserPosted(output)) => assert_eq!(output.message, posted.message),
+                    output => panic!("unexpected output: {:?}", output),
+                }
+
+                let response = warp::test::request()
+                    .path("/messages?limit=10")
+                    .reply(&routes)
+                    .await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let history: HistoryOutput = serde_json::from_slice(response.body()).unwrap();
+                assert_eq!(history.room_id, room_id);
+                assert_eq!(history.messages, vec![posted.message]);
+                assert_eq!(history.cursor, None);
+
+                let response = warp::test::request()
+                    .path("/messages?limit=0")
+                    .reply(&routes)
+                    .await;
+                let history: HistoryOutput = serde_json::from_slice(response.body()).unwrap();
+                assert_eq!(history.messages.len(), 1);
+
+                let response = warp::test::request()
+                    .path(&format!("/messages?roomId={}", Uuid::new_v4()))
+                    .reply(&routes)
+                    .await;
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+                let response = warp::test::request().path("/users").reply(&routes).await;
+                let users: Vec<UserOutput> = serde_json::from_slice(response.body()).unwrap();
+                let names: Vec<&str> = users.iter().map(|user| user.name.as_str()).collect();
+                assert_eq!(names, vec!["John", "Service"]);
+            };
+            tokio::select! {
+                _ = hub.run(receiver) => {},
+                _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn authenticated_reads() {
+        let hub = Arc::new(Hub::new(HubOptions {
+            authenticator: Some(Arc::new(TokenAuthenticator::new(b"secret"))),
+            ..Default::default()
+        }));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let options = ApiOptions {
+            token: String::from("secret"),
+            user_name: String::from("Service"),
+        };
+        let service = Arc::new(ServiceClient::new(options, hub.clone(), sender));
+        let routes = api::routes(hub.clone(), Some(service), 1024);
+        let room_id = hub.default_room_id();
+
+        Runtime::new().unwrap().block_on(async move {
+            let case = async {
+                // Reading needs the token once users have to prove their names
+                for path in &["/messages", "/users"] {
+                    let response = warp::test::request().path(path).reply(&routes).await;
+                    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                }
+
+                let response = warp::test::request()
+                    .path("/messages")
+                    .header("authorization", "Bearer secret")
+                    .reply(&routes)
+                    .await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let history: HistoryOutput = serde_json::from_slice(response.body()).unwrap();
+                assert_eq!(history.room_id, room_id);
+                assert!(history.messages.is_empty());
+
+                // The service reads as a member, like anyone else
+                let response = warp::test::request()
+                    .path("/users")
+                    .header("authorization", "Bearer secret")
+                    .reply(&routes)
+                    .await;
+                let users: Vec<UserOutput> = serde_json::from_slice(response.body()).unwrap();
+                let names: Vec<&str> = users.iter().map(|user| user.name.as_str()).collect();
+                assert_eq!(names, vec!["Service"]);
+
+                let response = warp::test::request()
+                    .path(&format!("/messages?roomId={}", Uuid::new_v4()))
+                    .header("authorization", "Bearer secret")
+                    .reply(&routes)
+                    .await;
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            };
+            tokio::select! {
+                _ = hub.run(receiver) => {},
+                _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn reject_post() {
+        let hub = Arc::new(Hub::new(HubOptions {
+            reserved_names: vec![String::from("Service")],
+            ..Default::default()
+        }));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let options = ApiOptions {
+            token: String::from("secret"),
+            user_name: String::from("Service"),
+        };
+        let service = Arc::new(ServiceClient::new(options, hub.clone(), sender.clone()));
+        let routes = api::routes(hub.clone(), Some(service), 1024);
+
+        Runtime::new().unwrap().block_on(async move {
+            let case = async {
+                // The service's name is kept for it
+                let john_id = Uuid::new_v4();
+                let john = hub.connect(john_id, None);
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Join(JoinInput {
+                            name: String::from("Service"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    john.recv().await,
+                    Some(Output::Error(OutputError::NameTaken))
+                );
+
+                let bodies = vec![
+                    (r#"{"body":""}"#.to_string(), StatusCode::BAD_REQUEST),
+                    (
+                        r#"{"body":"Hi","nonce":""}"#.to_string(),
+                        StatusCode::BAD_REQUEST,
+                    ),
+                    (
+                        format!(r#"{{"body":"Hi","replyTo":"{}"}}"#, Uuid::new_v4()),
+                        StatusCode::NOT_FOUND,
+                    ),
+                    (r#"{"body":"Hi"}"#.to_string(), StatusCode::CREATED),
+                ];
+                for (body, status) in bodies {
+                    let response = warp::test::request()
+                        .method("POST")
+                        .path("/messages")
+                        .header("authorization", "Bearer secret")
+                        .body(&body)
+                        .reply(&routes)
+                        .await;
+                    assert_eq!(response.status(), status, "{}", body);
+                }
+            };
+            tokio::select! {
+                _ = hub.run(receiver) => {},
+                _ = case => {},
+            }
+        });
+    }
+}
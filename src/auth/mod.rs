@@ -0,0 +1,20 @@
+pub mod password;
+pub mod token;
+
+pub use password::PasswordFile;
+pub use token::TokenAuthenticator;
+
+/// Decides whether a client may join under the name it asked for.
+pub trait Authenticator: Send + Sync {
+    /// Checks `credential`, such as a password or a signed token, against
+    /// `name`.
+    fn authenticate(&self, name: &str, credential: Option<&str>) -> bool;
+}
+
+// Passing any one of several authenticators is enough
+impl Authenticator for Vec<Box<dyn Authenticator>> {
+    fn authenticate(&self, name: &str, credential: Option<&str>) -> bool {
+        self.iter()
+            .any(|authenticator| authenticator.authenticate(name, credential))
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::auth::Authenticator;
+use crate::error::{Error, Result};
+
+const SCHEME: &str = "pbkdf2-sha256";
+const ITERATIONS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+static ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+/// Users and their password hashes, one `name:hash` pair per line. Only the
+/// users listed may join.
+///
+/// Blank lines and lines starting with `#` are ignored. Hashes are written
+/// as `pbkdf2-sha256$<iterations>$<salt>$<hash>`, with the salt and hash in
+/// unpadded base64, as produced by [`hash_password`].
+pub struct PasswordFile {
+    hashes: HashMap<String, PasswordHash>,
+}
+
+impl PasswordFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut hashes = HashMap::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || Error::System(format!("invalid password entry on line {}", index + 1));
+            let (name, hash) = line.split_once(':').ok_or_else(invalid)?;
+            let hash = PasswordHash::parse(hash.trim()).ok_or_else(invalid)?;
+            hashes.insert(String::from(name.trim()), hash);
+        }
+        Ok(PasswordFile { hashes })
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate(&self, name: &str, credential: Option<&str>) -> bool {
+        match (self.hashes.get(name), credential) {
+            (Some(hash), Some(password)) => hash.verify(password),
+            _ => false,
+        }
+    }
+}
+
+impl PasswordHash {
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split('$');
+        if parts.next()? != SCHEME {
+            return None;
+        }
+        let iterations = NonZeroU32::new(parts.next()?.parse().ok()?)?;
+        let salt = base64::decode_config(parts.next()?, base64::STANDARD_NO_PAD).ok()?;
+        let hash = base64::decode_config(parts.next()?, base64::STANDARD_NO_PAD).ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(PasswordHash {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            ALGORITHM,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+/// Hashes `password` with a random salt, for use in a password file.
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0u8; SALT_LENGTH];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| Error::System(String::from("failed to generate a salt")))?;
+    let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+    let mut hash = [0u8; HASH_LENGTH];
+    pbkdf2::derive(ALGORITHM, iterations, &salt, password.as_bytes(), &mut hash);
+    Ok(format!(
+        "{}${}${}${}",
+        SCHEME,
+        iterations,
+        base64::encode_config(salt, base64::STANDARD_NO_PAD),
+        base64::encode_config(hash, base64::STANDARD_NO_PAD),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::password::{hash_password, PasswordFile};
+    use crate::auth::Authenticator;
+
+    #[test]
+    fn verify() {
+        let contents = format!(
+            "# Staff\nAlice:{}\n\nBob : {}\n",
+            hash_password("wonderland").unwrap(),
+            hash_password("builder").unwrap(),
+        );
+        let passwords = PasswordFile::parse(&contents).unwrap();
+
+        assert!(passwords.authenticate("Alice", Some("wonderland")));
+        assert!(passwords.authenticate("Bob", Some("builder")));
+        assert!(!passwords.authenticate("Alice", Some("builder")));
+        assert!(!passwords.authenticate("Alice", None));
+        // Names missing from the file cannot join at all
+        assert!(!passwords.authenticate("Carol", Some("wonderland")));
+        assert!(!passwords.authenticate("Carol", None));
+
+        assert!(PasswordFile::parse("Alice").is_err());
+        assert!(PasswordFile::parse("Alice:wonderland").is_err());
+    }
+}
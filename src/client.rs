@@ -1,66 +1,172 @@
-use std::time::Duration;
-use std::{error, result};
+use std::sync::Arc;
 
-use futures::stream::SplitStream;
-use futures::{future, Stream, StreamExt, TryStream, TryStreamExt};
-use tokio::time;
+use futures::stream::{self, SplitStream};
+use futures::{future, Stream, StreamExt};
 use uuid::Uuid;
 use warp::filters::ws::WebSocket;
 
+use crate::codec::Codec;
 use crate::error::{Error, Result};
-use crate::proto::{InputParcel, OutputParcel};
+use crate::outbox::Outbox;
+use crate::proto::InputParcel;
+
+// Status code of a close frame sent by a client that is done for good
+const NORMAL_CLOSURE: u16 = 1000;
 
 #[derive(Clone, Copy, Default)]
 pub struct Client {
     pub id: Uuid,
+    pub codec: Codec,
+}
+
+/// Close frame sent by a client, explaining why it went away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Something a client sent over its WebSocket.
+#[derive(Debug)]
+pub enum Received {
+    Input(InputParcel),
+    /// A data frame that does not hold a valid input.
+    Malformed(Error),
+    /// The client closed the connection, with a close frame if it sent one.
+    Closed(Option<CloseFrame>),
 }
 
 impl Client {
-    pub fn new() -> Self {
-        Client { id: Uuid::new_v4() }
+    pub fn new(codec: Codec) -> Self {
+        Client {
+            id: Uuid::new_v4(),
+            codec,
+        }
     }
 
     pub fn read_input(
         &self,
         stream: SplitStream<WebSocket>,
-    ) -> impl Stream<Item = Result<InputParcel>> {
+    ) -> impl Stream<Item = Result<Received>> {
         let client_id = self.id;
-        time::throttle(
-            Duration::from_millis(300),
-            stream
-                // Take only text messages
-                .take_while(|message| {
-                    future::ready(if let Ok(message) = message {
-                        message.is_text()
-                    } else {
-                        false
-                    })
-                })
-                // Deserialize JSON messages into proto::Input
-                .map(move |message| match message {
-                    Err(err) => Err(Error::System(err.to_string())),
-                    Ok(message) => {
-                        let input = serde_json::from_str(message.to_str().unwrap())?;
-                        Ok(InputParcel::new(client_id, input))
-                    }
+        let codec = self.codec;
+        stream.filter_map(move |message| {
+            future::ready(match message {
+                Err(err) => Some(Err(Error::System(err.to_string()))),
+                Ok(message) if message.is_close() => Some(Ok(Received::Closed(
+                    message
+                        .close_frame()
+                        .map(|(code, reason)| CloseFrame::new(code, reason)),
+                ))),
+                // Either kind of data frame may hold an input, whatever the codec
+                Ok(message) if message.is_text() || message.is_binary() => {
+                    Some(Ok(match codec.decode(message.as_bytes()) {
+                        Ok(input) => Received::Input(InputParcel::new(client_id, input)),
+                        Err(err) => Received::Malformed(err),
+                    }))
+                }
+                // Pings are answered by the WebSocket itself, and pongs need no answer
+                Ok(_) => None,
+            })
+        })
+    }
+
+    pub fn write_output(
+        &self,
+        outbox: Arc<Outbox>,
+    ) -> impl Stream<Item = Result<warp::ws::Message>> {
+        let codec = self.codec;
+        stream::unfold(Some(outbox), move |outbox| async move {
+            let outbox = outbox?;
+            match outbox.recv_parcel().await {
+                Some(parcel) => Some((codec.encode(&parcel), Some(outbox))),
+                // Tell the client why the hub closed the connection
+                None => outbox.close_reason().map(|reason| {
+                    let message = warp::ws::Message::close_with(reason.code(), reason.as_str());
+                    (Ok(message), None)
                 }),
-        )
+            }
+        })
+    }
+}
+
+impl CloseFrame {
+    pub fn new(code: u16, reason: &str) -> Self {
+        CloseFrame {
+            code,
+            reason: String::from(reason),
+        }
     }
 
-    pub fn write_output<S, E>(&self, stream: S) -> impl Stream<Item = Result<warp::ws::Message>>
-    where
-        S: TryStream<Ok = OutputParcel, Error = E> + Stream<Item = result::Result<OutputParcel, E>>,
-        E: error::Error,
-    {
-        let client_id = self.id;
-        stream
-            // Skip irrelevant parcels
-            .try_filter(move |output_parcel| future::ready(output_parcel.client_id == client_id))
-            // Serialize to JSON
-            .map_ok(|output_parcel| {
-                let data = serde_json::to_string(&output_parcel.output).unwrap();
-                warp::ws::Message::text(data)
+    /// Whether the client left on purpose, rather than because of a network
+    /// or browser hiccup it may want to resume from.
+    pub fn is_normal(&self) -> bool {
+        self.code == NORMAL_CLOSURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::runtime::Runtime;
+    use tokio::sync::mpsc;
+    use warp::ws::{Message, Ws};
+    use warp::Filter;
+
+    use crate::client::{Client, CloseFrame, Received};
+    use crate::codec::Codec;
+    use crate::proto::Input;
+
+    #[test]
+    fn read_input() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let route = warp::ws().map(move |ws: Ws| {
+            let sender = sender.clone();
+            ws.on_upgrade(move |web_socket| async move {
+                let (_, stream) = web_socket.split();
+                Client::new(Codec::Json)
+                    .read_input(stream)
+                    .for_each(|received| {
+                        sender.send(received.unwrap()).unwrap();
+                        async {}
+                    })
+                    .await;
             })
-            .map_err(|err| Error::System(err.to_string()))
+        });
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut web_socket = warp::test::ws().handshake(route).await.unwrap();
+            web_socket.send_text(r#"{"type":"list-rooms"}"#).await;
+            web_socket.send(Message::ping(Vec::new())).await;
+            web_socket.send(Message::pong(Vec::new())).await;
+            web_socket
+                .send(Message::binary(&br#"{"type":"list-rooms"}"#[..]))
+                .await;
+            web_socket.send_text("{").await;
+            web_socket.send(Message::binary(vec![0xff])).await;
+            web_socket.send(Message::close_with(1000u16, "bye")).await;
+
+            // Control frames neither end the stream nor show up in it
+            for _ in 0..2 {
+                assert!(matches!(
+                    receiver.recv().await,
+                    Some(Received::Input(input_parcel)) if matches!(input_parcel.input, Input::ListRooms)
+                ));
+            }
+            for _ in 0..2 {
+                assert!(matches!(
+                    receiver.recv().await,
+                    Some(Received::Malformed(_))
+                ));
+            }
+            match receiver.recv().await {
+                Some(Received::Closed(Some(close_frame))) => {
+                    assert_eq!(close_frame, CloseFrame::new(1000, "bye"));
+                    assert!(close_frame.is_normal());
+                }
+                received => panic!("Expected Received::Closed got {:?}", received),
+            }
+        });
     }
 }
@@ -0,0 +1,320 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use warp::ws::Message;
+
+use crate::error::{Error, Result};
+
+const CODECS: [Codec; 3] = [Codec::Json, Codec::MessagePack, Codec::Cbor];
+
+/// Wire format of a client's inputs and outputs, picked with the
+/// `Sec-WebSocket-Protocol` header when it connects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON in text frames, for clients that do not ask for anything else.
+    #[default]
+    Json,
+    /// MessagePack in binary frames, with structs written as maps.
+    MessagePack,
+    /// CBOR in binary frames.
+    Cbor,
+}
+
+impl Codec {
+    /// Picks the first of the client's comma-separated subprotocols that the
+    /// server speaks.
+    pub fn negotiate(protocols: &str) -> Option<Codec> {
+        protocols
+            .split(',')
+            .map(str::trim)
+            .find_map(Codec::from_protocol)
+    }
+
+    /// Comma-separated subprotocols the server speaks, for telling clients
+    /// that offered none of them.
+    pub fn supported() -> String {
+        CODECS
+            .iter()
+            .map(|codec| codec.protocol())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Codec> {
+        CODECS
+            .iter()
+            .copied()
+            .find(|codec| codec.protocol() == protocol)
+    }
+
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+            Codec::Cbor => "cbor",
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Message> {
+        match self {
+            Codec::Json => Ok(Message::text(serde_json::to_string(value)?)),
+            // Named fields keep the output readable by any MessagePack library
+            Codec::MessagePack => rmp_serde::to_vec_named(value)
+                .map(Message::binary)
+                .map_err(|err| Error::Encoding(err.to_string())),
+            Codec::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(value, &mut buffer)
+                    .map_err(|err| Error::Encoding(err.to_string()))?;
+                Ok(Message::binary(buffer))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+            Codec::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|err| Error::Encoding(err.to_string()))
+            }
+            Codec::Cbor => {
+                ciborium::de::from_reader(bytes).map_err(|err| Error::Encoding(err.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use chrono::Utc;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use uuid::Uuid;
+
+    use crate::codec::{Codec, CODECS};
+    use crate::proto::{
+        BanInput, BanTarget, Capability, CatchUpInput, CaughtUpOutput, CreateRoomInput,
+        DeleteMessageInput, DirectMessageInput, DirectMessageOutput, EditMessageInput,
+        FetchHistoryInput, FetchThreadInput, HelloInput, HelloOutput, HistoryOutput, Input,
+        JoinInput, JoinRoomInput, JoinedOutput, KickInput, LeaveRoomInput, LeftOutput,
+        MessageDeletedOutput, MessageEditedOutput, MessageOutput, ModeratedOutput,
+        ModerationAction, MuteInput, Output, OutputError, OutputParcel, PostInput, PostedOutput,
+        ReactInput, ReactionOutput, ReactionsChangedOutput, RepliesChangedOutput, ResumeInput,
+        ResumedOutput, RoomCreatedOutput, RoomOutput, RoomsOutput, ThreadOutput, TypingInput,
+        UserJoinedOutput, UserLeftOutput, UserOutput, UserPostedOutput, UserTypingOutput,
+        PROTOCOL_VERSION,
+    };
+
+    fn assert_round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+        for codec in &CODECS {
+            let message = codec.encode(value).unwrap();
+            assert_eq!(message.is_text(), *codec == Codec::Json);
+            let decoded: T = codec.decode(message.as_bytes()).unwrap();
+            assert_eq!(&decoded, value, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let id = Uuid::new_v4();
+        let inputs = vec![
+            Input::Join(JoinInput {
+                name: String::from("John"),
+                credential: Some(String::from("secret")),
+            }),
+            Input::Resume(ResumeInput {
+                token: String::from("token"),
+            }),
+            Input::Post(PostInput {
+                room_id: id,
+                body: String::from("Hello"),
+                nonce: Some(String::from("1")),
+                reply_to: Some(id),
+            }),
+            Input::CreateRoom(CreateRoomInput {
+                name: String::from("Rust"),
+            }),
+            Input::JoinRoom(JoinRoomInput { room_id: id }),
+            Input::LeaveRoom(LeaveRoomInput { room_id: id }),
+            Input::ListRooms,
+            Input::DirectMessage(DirectMessageInput {
+                to: id,
+                body: String::from("Hi"),
+            }),
+            Input::FetchHistory(FetchHistoryInput {
+                room_id: id,
+                before: Some(id),
+                limit: 10,
+            }),
+            Input::EditMessage(EditMessageInput {
+                room_id: id,
+                message_id: id,
+                body: String::from("Hello!"),
+            }),
+            Input::DeleteMessage(DeleteMessageInput {
+                room_id: id,
+                message_id: id,
+            }),
+            Input::Typing(TypingInput { active: true }),
+            Input::Kick(KickInput { user_id: id }),
+            Input::Mute(MuteInput {
+                user_id: id,
+                duration_secs: 60,
+            }),
+            Input::Ban(BanInput {
+                target: BanTarget::Name(String::from("Jane")),
+                duration_secs: 60,
+            }),
+            Input::Ban(BanInput {
+                target: BanTarget::Address(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                duration_secs: 60,
+            }),
+            Input::Hello(HelloInput {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![String::from("typing")],
+            }),
+            Input::CatchUp(CatchUpInput { since_seq: 3 }),
+            Input::React(ReactInput {
+                room_id: id,
+                message_id: id,
+                emoji: String::from("👍"),
+            }),
+            Input::Unreact(ReactInput {
+                room_id: id,
+                message_id: id,
+                emoji: String::from("👍"),
+            }),
+            Input::FetchThread(FetchThreadInput { message_id: id }),
+        ];
+        for input in &inputs {
+            assert_round_trip(input);
+        }
+
+        let user = UserOutput::new(id, "John");
+        let room = RoomOutput::new(id, "General");
+        let reactions = vec![ReactionOutput::new("👍", vec![id])];
+        let message = MessageOutput::new(
+            id,
+            user.clone(),
+            "Hello",
+            Utc::now(),
+            None,
+            false,
+            reactions.clone(),
+        );
+        let edited = MessageOutput::new(
+            id,
+            user.clone(),
+            "",
+            Utc::now(),
+            Some(Utc::now()),
+            true,
+            Vec::new(),
+        );
+        let reply = MessageOutput::new(
+            Uuid::new_v4(),
+            user.clone(),
+            "Hi",
+            Utc::now(),
+            None,
+            false,
+            Vec::new(),
+        )
+        .with_thread(Some(id), Some(id), 0);
+        let errors = vec![
+            OutputError::NameTaken,
+            OutputError::InvalidName,
+            OutputError::NotJoined,
+            OutputError::AlreadyJoined,
+            OutputError::InvalidMessageBody,
+            OutputError::RoomNameTaken,
+            OutputError::InvalidRoomName,
+            OutputError::RoomNotFound,
+            OutputError::NotInRoom,
+            OutputError::UnknownRecipient,
+            OutputError::Internal,
+            OutputError::MessageNotFound,
+            OutputError::NotMessageAuthor,
+            OutputError::InvalidSession,
+            OutputError::NotModerator,
+            OutputError::UserNotFound,
+            OutputError::Muted,
+            OutputError::Banned,
+            OutputError::RateLimited {
+                retry_after_ms: 250,
+            },
+            OutputError::Unauthorized,
+            OutputError::MalformedInput,
+            OutputError::UnsupportedVersion {
+                min_version: 1,
+                max_version: 2,
+            },
+            OutputError::CatchUpUnavailable { last_seq: 3 },
+            OutputError::InvalidEmoji,
+            OutputError::TooManyReactions,
+        ];
+        let mut outputs: Vec<Output> = errors.into_iter().map(Output::Error).collect();
+        outputs.extend(vec![
+            Output::Alive,
+            Output::Joined(JoinedOutput::new(
+                room.clone(),
+                user.clone(),
+                vec![UserOutput::new(Uuid::new_v4(), "Jane")],
+                vec![message.clone(), edited.clone()],
+                Some(id),
+                Some(Utc::now()),
+                Some(String::from("token")),
+            )),
+            Output::Resumed(ResumedOutput::new(user.clone(), vec![room.clone()])),
+            Output::UserJoined(UserJoinedOutput::new(id, user.clone())),
+            Output::UserLeft(UserLeftOutput::new(id, id)),
+            Output::Posted(PostedOutput::new(
+                id,
+                message.clone(),
+                Some(String::from("1")),
+            )),
+            Output::UserPosted(UserPostedOutput::new(id, message.clone())),
+            Output::RoomCreated(RoomCreatedOutput::new(room.clone())),
+            Output::Left(LeftOutput::new(id)),
+            Output::Rooms(RoomsOutput::new(vec![room])),
+            Output::DirectMessage(DirectMessageOutput::new(id, message.clone())),
+            Output::History(HistoryOutput::new(id, vec![message.clone()], None, None)),
+            Output::MessageEdited(MessageEditedOutput::new(id, edited)),
+            Output::MessageDeleted(MessageDeletedOutput::new(id, id)),
+            Output::UserTyping(UserTypingOutput::new(id, false)),
+            Output::Moderated(ModeratedOutput::new(
+                ModerationAction::Ban,
+                user.clone(),
+                vec![user],
+                Some(Utc::now()),
+            )),
+            Output::Hello(HelloOutput::new(PROTOCOL_VERSION, Capability::ALL.to_vec())),
+            Output::CaughtUp(CaughtUpOutput::new(3)),
+            Output::ReactionsChanged(ReactionsChangedOutput::new(id, id, reactions)),
+            Output::UserReplied(UserPostedOutput::new(id, reply.clone())),
+            Output::RepliesChanged(RepliesChangedOutput::new(id, id, 1)),
+            Output::Thread(ThreadOutput::new(
+                id,
+                id,
+                Some(message.with_thread(None, None, 1)),
+                vec![reply],
+            )),
+        ]);
+        for output in &outputs {
+            assert_round_trip(output);
+            assert_round_trip(&OutputParcel::new(None, output.clone()));
+            assert_round_trip(&OutputParcel::new(Some(3), output.clone()));
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(Codec::negotiate("msgpack"), Some(Codec::MessagePack));
+        // The client's preference wins
+        assert_eq!(Codec::negotiate("xml, cbor, json"), Some(Codec::Cbor));
+        assert_eq!(Codec::negotiate("xml"), None);
+        assert_eq!(Codec::supported(), "json, msgpack, cbor");
+    }
+}
@@ -0,0 +1,401 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::api::ApiOptions;
+use crate::auth::{Authenticator, PasswordFile, TokenAuthenticator};
+use crate::error::{Error, Result};
+use crate::hub::HubOptions;
+use crate::limiter::RateLimit;
+use crate::model::feed::FEED_CAPACITY;
+use crate::outbox::LagPolicy;
+use crate::server::ServerOptions;
+use crate::storage::{FileStorage, MemoryStorage, Retention, Storage};
+use crate::tls::TlsOptions;
+
+const DEFAULT_ALIVE_INTERVAL_SECS: u64 = 5;
+const DEFAULT_RESUME_WINDOW_SECS: u64 = 30;
+const DEFAULT_API_USER_NAME: &str = "Service";
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const DEDUPE_WINDOW: Duration = Duration::from_secs(300);
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+const RATE_LIMIT: RateLimit = RateLimit {
+    burst: 5,
+    interval: Duration::from_millis(500),
+};
+
+/// Server settings, each taken from a command-line flag, an environment
+/// variable or the TOML file passed with `--config`, in that order.
+#[derive(Debug, Default, Deserialize, StructOpt)]
+#[serde(deny_unknown_fields)]
+#[structopt(name = "rusty-chat")]
+pub struct Config {
+    /// Path to a TOML configuration file
+    #[serde(skip)]
+    #[structopt(long, env = "CONFIG_PATH")]
+    pub config: Option<PathBuf>,
+    /// Read a password from standard input, print its hash for a password file and exit
+    #[serde(skip)]
+    #[structopt(long)]
+    pub hash_password: bool,
+    /// Address to listen on [default: 127.0.0.1]
+    #[structopt(long, env = "ADDRESS")]
+    pub address: Option<IpAddr>,
+    /// Port to listen on [default: 8080]
+    #[structopt(long, env = "PORT")]
+    pub port: Option<u16>,
+    /// PEM certificate chain, enables TLS together with --tls-key-path
+    #[structopt(long, env = "TLS_CERT_PATH")]
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key for the certificate
+    #[structopt(long, env = "TLS_KEY_PATH")]
+    pub tls_key_path: Option<PathBuf>,
+    /// Append-only message log, messages are kept in memory if not set
+    #[structopt(long, env = "STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+    /// File of `name:hash` lines, only users listed there may join, with their password
+    #[structopt(long, env = "PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+    /// Secret for HMAC-signed tokens that users may join with instead of a password
+    #[structopt(long, env = "TOKEN_SECRET")]
+    pub token_secret: Option<String>,
+    /// Bearer token for posting over HTTP, which is disabled if not set
+    #[structopt(long, env = "API_TOKEN")]
+    pub api_token: Option<String>,
+    /// Name that messages posted over HTTP appear under [default: Service]
+    #[structopt(long, env = "API_USER_NAME")]
+    pub api_user_name: Option<String>,
+    /// Comma-separated names of users that join as moderators, needs --password-file or --token-secret
+    #[structopt(long, env = "MODERATORS", use_delimiter = true)]
+    pub moderators: Option<Vec<String>>,
+    /// Seconds between keep-alive messages, 0 disables them [default: 5]
+    #[structopt(long, env = "ALIVE_INTERVAL_SECS")]
+    pub alive_interval_secs: Option<u64>,
+    /// Seconds a disconnected user may resume their session, 0 disables resuming [default: 30]
+    #[structopt(long, env = "RESUME_WINDOW_SECS")]
+    pub resume_window_secs: Option<u64>,
+    /// Largest accepted WebSocket frame in bytes [default: 65536]
+    #[structopt(long, env = "MAX_FRAME_SIZE")]
+    pub max_frame_size: Option<usize>,
+    /// Longest accepted message body in bytes [default: 256]
+    #[structopt(long, env = "MAX_MESSAGE_BODY_LENGTH")]
+    pub max_message_body_length: Option<usize>,
+    /// Outputs queued per client before it is considered lagging [default: 64]
+    #[structopt(long, env = "OUTBOX_CAPACITY")]
+    pub outbox_capacity: Option<usize>,
+    /// Inputs a client may send in a burst before it is rate limited, 0 disables the limit [default: 5]
+    #[structopt(long, env = "RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+    /// Milliseconds it takes a rate-limited client to earn another input [default: 500]
+    #[structopt(long, env = "RATE_LIMIT_INTERVAL_MS")]
+    pub rate_limit_interval_ms: Option<u64>,
+    /// Most messages held in memory per room, the oldest are dropped as new ones arrive [default: 100000]
+    #[structopt(long, env = "FEED_CAPACITY")]
+    pub feed_capacity: Option<usize>,
+    /// Most messages kept per room, older ones are pruned every minute
+    #[structopt(long, env = "RETENTION_MAX_MESSAGES")]
+    pub retention_max_messages: Option<usize>,
+    /// Seconds messages are kept for
+    #[structopt(long, env = "RETENTION_MAX_AGE_SECS")]
+    pub retention_max_age_secs: Option<u64>,
+    /// Most bytes of message bodies kept per room
+    #[structopt(long, env = "RETENTION_MAX_BYTES")]
+    pub retention_max_bytes: Option<usize>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let config = Config::from_args();
+        match &config.config {
+            Some(path) => {
+                let file = Config::from_file(path)?;
+                Ok(config.or(file))
+            }
+            None => Ok(config),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    pub fn server_options(&self) -> Result<ServerOptions> {
+        let defaults = ServerOptions::default();
+        let tls = match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(TlsOptions {
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(Error::System(String::from(
+                    "TLS needs both a certificate and a key path",
+                )))
+            }
+        };
+        Ok(ServerOptions {
+            address: SocketAddr::new(
+                self.address.unwrap_or_else(|| defaults.address.ip()),
+                self.port.unwrap_or_else(|| defaults.address.port()),
+            ),
+            max_frame_size: self.max_frame_size.unwrap_or(defaults.max_frame_size),
+            tls,
+            api: self.api_token.as_ref().map(|token| ApiOptions {
+                token: token.clone(),
+                user_name: self.api_user_name(),
+            }),
+        })
+    }
+
+    pub fn hub_options(&self) -> Result<HubOptions> {
+        let defaults = HubOptions::default();
+        // Either way of proving a name is enough when both are set up
+        let mut authenticators: Vec<Box<dyn Authenticator>> = Vec::new();
+        if let Some(path) = &self.password_file {
+            authenticators.push(Box::new(PasswordFile::open(path)?));
+        }
+        if let Some(secret) = &self.token_secret {
+            authenticators.push(Box::new(TokenAuthenticator::new(secret.as_bytes())));
+        }
+        let authenticator: Option<Arc<dyn Authenticator>> = if authenticators.is_empty() {
+            None
+        } else {
+            Some(Arc::new(authenticators))
+        };
+        let moderators: Vec<String> = self
+            .moderators
+            .iter()
+            .flatten()
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+        // Otherwise whoever joins first under a moderator's name gets their rights
+        if !moderators.is_empty() && authenticator.is_none() {
+            return Err(Error::System(String::from(
+                "moderators need a password file or token secret to prove their names",
+            )));
+        }
+        let outbox_capacity = self.outbox_capacity.unwrap_or(defaults.outbox_capacity);
+        // Otherwise every client would be lagging as soon as it is sent anything
+        if outbox_capacity == 0 {
+            return Err(Error::System(String::from(
+                "outbox capacity must be at least one output",
+            )));
+        }
+        let rate_limit = match self.rate_limit_burst.unwrap_or(RATE_LIMIT.burst) {
+            0 => None,
+            burst => Some(RateLimit {
+                burst,
+                interval: self
+                    .rate_limit_interval_ms
+                    .map_or(RATE_LIMIT.interval, Duration::from_millis),
+            }),
+        };
+        if rate_limit.is_some_and(|rate_limit| rate_limit.interval.is_zero()) {
+            return Err(Error::System(String::from(
+                "rate limit interval must be at least a millisecond",
+            )));
+        }
+        // Keep the HTTP service's name from being taken over a WebSocket
+        let reserved_names = if self.api_token.is_some() {
+            vec![self.api_user_name()]
+        } else {
+            Vec::new()
+        };
+        Ok(HubOptions {
+            alive_interval: seconds(
+                self.alive_interval_secs
+                    .unwrap_or(DEFAULT_ALIVE_INTERVAL_SECS),
+            ),
+            resume_window: seconds(
+                self.resume_window_secs
+                    .unwrap_or(DEFAULT_RESUME_WINDOW_SECS),
+            ),
+            typing_timeout: Some(TYPING_TIMEOUT),
+            lag_policy: LagPolicy::Disconnect,
+            rate_limit,
+            dedupe_window: Some(DEDUPE_WINDOW),
+            journal_capacity: defaults.journal_capacity,
+            retention: self.retention(),
+            moderators,
+            reserved_names,
+            authenticator,
+            max_message_body_length: self
+                .max_message_body_length
+                .unwrap_or(defaults.max_message_body_length),
+            outbox_capacity,
+        })
+    }
+
+    pub fn storage(&self) -> Result<Box<dyn Storage>> {
+        let feed_capacity = self.feed_capacity.unwrap_or(FEED_CAPACITY);
+        if feed_capacity == 0 {
+            return Err(Error::System(String::from(
+                "feed capacity must be at least one message",
+            )));
+        }
+        Ok(match &self.storage_path {
+            Some(path) => Box::new(FileStorage::with_feed_capacity(path, feed_capacity)?),
+            None => Box::new(MemoryStorage::with_feed_capacity(feed_capacity)),
+        })
+    }
+
+    // Settings missing here are taken from `other`
+    fn or(self, other: Config) -> Self {
+        Config {
+            config: self.config.or(other.config),
+            hash_password: self.hash_password,
+            address: self.address.or(other.address),
+            port: self.port.or(other.port),
+            tls_cert_path: self.tls_cert_path.or(other.tls_cert_path),
+            tls_key_path: self.tls_key_path.or(other.tls_key_path),
+            storage_path: self.storage_path.or(other.storage_path),
+            password_file: self.password_file.or(other.password_file),
+            token_secret: self.token_secret.or(other.token_secret),
+            api_token: self.api_token.or(other.api_token),
+            api_user_name: self.api_user_name.or(other.api_user_name),
+            moderators: self.moderators.or(other.moderators),
+            alive_interval_secs: self.alive_interval_secs.or(other.alive_interval_secs),
+            resume_window_secs: self.resume_window_secs.or(other.resume_window_secs),
+            max_frame_size: self.max_frame_size.or(other.max_frame_size),
+            max_message_body_length: self
+                .max_message_body_length
+                .or(other.max_message_body_length),
+            outbox_capacity: self.outbox_capacity.or(other.outbox_capacity),
+            rate_limit_burst: self.rate_limit_burst.or(other.rate_limit_burst),
+            rate_limit_interval_ms: self.rate_limit_interval_ms.or(other.rate_limit_interval_ms),
+            feed_capacity: self.feed_capacity.or(other.feed_capacity),
+            retention_max_messages: self.retention_max_messages.or(other.retention_max_messages),
+            retention_max_age_secs: self.retention_max_age_secs.or(other.retention_max_age_secs),
+            retention_max_bytes: self.retention_max_bytes.or(other.retention_max_bytes),
+        }
+    }
+
+    // History is kept for as long as memory allows unless a limit is set
+    fn retention(&self) -> Option<Retention> {
+        let retention = Retention {
+            max_messages: self.retention_max_messages,
+            max_age: self.retention_max_age_secs.map(Duration::from_secs),
+            max_bytes: self.retention_max_bytes,
+            interval: RETENTION_INTERVAL,
+        };
+        if retention.max_messages.is_none()
+            && retention.max_age.is_none()
+            && retention.max_bytes.is_none()
+        {
+            None
+        } else {
+            Some(retention)
+        }
+    }
+
+    fn api_user_name(&self) -> String {
+        self.api_user_name
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_API_USER_NAME))
+    }
+}
+
+// Zero turns the timer off
+fn seconds(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use structopt::StructOpt;
+
+    use crate::config::Config;
+    use crate::limiter::RateLimit;
+
+    #[test]
+    fn precedence() {
+        let flags = Config::from_iter_safe(&[
+            "rusty-chat",
+            "--port",
+            "9000",
+            "--moderators",
+            "Alice, Bob",
+            "--resume-window-secs",
+            "0",
+            "--token-secret",
+            "secret",
+            "--rate-limit-burst",
+            "10",
+        ])
+        .unwrap();
+        let file: Config = toml::from_str(
+            r#"
+            port = 8000
+            address = "0.0.0.0"
+            moderators = ["Carol"]
+            max_message_body_length = 512
+            retention_max_messages = 1000
+            rate_limit_burst = 20
+            rate_limit_interval_ms = 250
+            "#,
+        )
+        .unwrap();
+        let config = flags.or(file);
+
+        // Flags win over the file, which wins over defaults
+        let server_options = config.server_options().unwrap();
+        assert_eq!(server_options.address.to_string(), "0.0.0.0:9000");
+        assert_eq!(server_options.max_frame_size, 1 << 16);
+        assert_eq!(server_options.tls, None);
+        assert_eq!(server_options.api, None);
+
+        let hub_options = config.hub_options().unwrap();
+        assert_eq!(hub_options.moderators, vec!["Alice", "Bob"]);
+        assert_eq!(hub_options.max_message_body_length, 512);
+        assert_eq!(hub_options.alive_interval, Some(Duration::from_secs(5)));
+        assert_eq!(hub_options.resume_window, None);
+        assert_eq!(
+            hub_options.rate_limit,
+            Some(RateLimit {
+                burst: 10,
+                interval: Duration::from_millis(250),
+            })
+        );
+        let retention = hub_options.retention.unwrap();
+        assert_eq!(retention.max_messages, Some(1000));
+        assert_eq!(retention.max_age, None);
+
+        assert!(toml::from_str::<Config>("prot = 8000").is_err());
+        // TLS needs both halves
+        let config: Config = toml::from_str(r#"tls_cert_path = "cert.pem""#).unwrap();
+        assert!(config.server_options().is_err());
+        // Moderators must be able to prove their names
+        let config: Config = toml::from_str(r#"moderators = ["Alice"]"#).unwrap();
+        assert!(config.hub_options().is_err());
+        // Clients must be able to queue something
+        let config: Config = toml::from_str("outbox_capacity = 0").unwrap();
+        assert!(config.hub_options().is_err());
+        let config: Config = toml::from_str("rate_limit_burst = 0").unwrap();
+        assert_eq!(config.hub_options().unwrap().rate_limit, None);
+        let config: Config = toml::from_str("rate_limit_interval_ms = 0").unwrap();
+        assert!(config.hub_options().is_err());
+        // Feeds must hold at least one message
+        let config: Config = toml::from_str("feed_capacity = 0").unwrap();
+        assert!(config.storage().is_err());
+    }
+
+    #[test]
+    fn example() {
+        let config = Config::from_file("config.example.toml").unwrap();
+        assert_eq!(config.port, Some(8080));
+    }
+}
@@ -5,6 +5,8 @@ pub enum Error {
     System(String),
     Io(io::Error),
     Message(serde_json::Error),
+    Config(toml::de::Error),
+    Encoding(String),
 }
 
 impl fmt::Display for Error {
@@ -13,6 +15,8 @@ impl fmt::Display for Error {
             Error::System(err) => write!(f, "system error: {}", err),
             Error::Io(ref err) => write!(f, "IO error: {}", err),
             Error::Message(ref err) => write!(f, "Invalid message: {}", err),
+            Error::Config(ref err) => write!(f, "Invalid configuration: {}", err),
+            Error::Encoding(err) => write!(f, "Invalid encoding: {}", err),
         }
     }
 }
@@ -31,4 +35,10 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Error::Config(err)
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
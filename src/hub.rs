@@ -1,82 +1,494 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{self, Arc};
 use std::time::Duration;
 
-use chrono::Utc;
-use futures::StreamExt;
+use chrono::{DateTime, Utc};
+use futures::{future, StreamExt};
+use log::{error, info, warn};
 use regex::Regex;
+use serde::Serialize;
 use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::{broadcast, RwLock};
-use tokio::time;
+use tokio::sync::RwLock;
+use tokio::task;
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
 
-use crate::model::feed::Feed;
+use crate::auth::Authenticator;
+use crate::client::CloseFrame;
+use crate::error::Error;
+use crate::journal::Journal;
+use crate::limiter::{RateLimit, RateLimiter};
+use crate::metrics::Metrics;
 use crate::model::message::Message;
-use crate::model::user::User;
+use crate::model::room::Room;
+use crate::model::session::Session;
+use crate::model::user::{Role, User};
+use crate::outbox::{CloseReason, LagPolicy, Outbox};
 use crate::proto::{
-    Input, InputParcel, JoinInput, JoinedOutput, MessageOutput, Output, OutputError, OutputParcel,
-    PostInput, PostedOutput, UserJoinedOutput, UserLeftOutput, UserOutput, UserPostedOutput,
+    BanInput, BanTarget, Capability, CatchUpInput, CaughtUpOutput, CreateRoomInput,
+    DeleteMessageInput, DirectMessageInput, DirectMessageOutput, EditMessageInput,
+    FetchHistoryInput, FetchThreadInput, HelloInput, HelloOutput, HistoryOutput, Input,
+    InputParcel, JoinInput, JoinRoomInput, JoinedOutput, KickInput, LeaveRoomInput, LeftOutput,
+    MessageDeletedOutput, MessageEditedOutput, MessageOutput, ModeratedOutput, ModerationAction,
+    MuteInput, Output, OutputError, OutputParcel, PostInput, PostedOutput, ReactInput,
+    ReactionOutput, ReactionsChangedOutput, RepliesChangedOutput, ResumeInput, ResumedOutput,
+    RoomCreatedOutput, RoomOutput, RoomsOutput, ThreadOutput, TypingInput, UserJoinedOutput,
+    UserLeftOutput, UserOutput, UserPostedOutput, UserTypingOutput, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, SEQ_PROTOCOL_VERSION,
 };
+use crate::storage::{MemoryStorage, Retention, Storage};
 
-const OUTPUT_CHANNEL_SIZE: usize = 16;
+const OUTBOX_CAPACITY: usize = 64;
 const MAX_MESSAGE_BODY_LENGTH: usize = 256;
+const JOIN_HISTORY_LENGTH: usize = 50;
+const MAX_HISTORY_PAGE_LENGTH: usize = 100;
+const MAX_NONCE_LENGTH: usize = 64;
+const JOURNAL_CAPACITY: usize = 256;
+const MAX_EMOJI_LENGTH: usize = 32;
+const MAX_MESSAGE_REACTIONS: usize = 20;
+const DEFAULT_ROOM_ID: Uuid = Uuid::nil();
+const DEFAULT_ROOM_NAME: &str = "General";
+// Longer mutes and bans are more likely mistakes, and would overflow the clock
+const MAX_MODERATION_DURATION: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+const STORAGE_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
 lazy_static! {
-    static ref USER_NAME_REGEX: Regex = Regex::new("[A-Za-z\\s]{4,24}").unwrap();
+    static ref USER_NAME_REGEX: Regex = Regex::new("^[A-Za-z\\s]{4,24}$").unwrap();
+    static ref ROOM_NAME_REGEX: Regex = Regex::new("^[A-Za-z0-9\\s]{2,24}$").unwrap();
 }
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone)]
 pub struct HubOptions {
     pub alive_interval: Option<Duration>,
+    pub resume_window: Option<Duration>,
+    pub typing_timeout: Option<Duration>,
+    pub lag_policy: LagPolicy,
+    pub rate_limit: Option<RateLimit>,
+    /// How long a post's nonce is remembered, so that a retried post is not
+    /// posted again. Posts are never deduplicated if not set.
+    pub dedupe_window: Option<Duration>,
+    /// Number of outputs kept for each user to catch up on after reconnecting.
+    pub journal_capacity: usize,
+    /// How much history is kept, all of it if not set.
+    pub retention: Option<Retention>,
+    /// Names of users that join as moderators.
+    pub moderators: Vec<String>,
+    /// Names kept for the server's own clients, such as the HTTP service,
+    /// that no one else may join with.
+    pub reserved_names: Vec<String>,
+    /// Checks that users own the names they join with, anyone may take any
+    /// free name if not set.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub max_message_body_length: usize,
+    pub outbox_capacity: usize,
+}
+
+/// Snapshot of the hub for health and readiness probes.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    pub running: bool,
+    /// Whether an input has been processing for too long.
+    pub stalled: bool,
+    pub last_processed_at: Option<DateTime<Utc>>,
+    pub storage_available: bool,
+    pub connected_clients: usize,
 }
 
 pub struct Hub {
     alive_interval: Option<Duration>,
-    output_sender: broadcast::Sender<OutputParcel>,
+    resume_window: Option<Duration>,
+    typing_timeout: Option<Duration>,
+    lag_policy: LagPolicy,
+    dedupe_window: Option<Duration>,
+    journal_capacity: usize,
+    retention: Option<Retention>,
+    moderators: Vec<String>,
+    reserved_names: Vec<String>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    max_message_body_length: usize,
+    outbox_capacity: usize,
+    rate_limiter: Option<RateLimiter>,
+    metrics: Metrics,
+    running: AtomicBool,
+    processing_since: sync::Mutex<Option<Instant>>,
+    last_processed_at: sync::Mutex<Option<DateTime<Utc>>>,
+    outboxes: sync::RwLock<HashMap<Uuid, Arc<Outbox>>>,
+    addresses: sync::RwLock<HashMap<Uuid, IpAddr>>,
+    // Clients connected by the server itself, which may take reserved names
+    service_clients: sync::RwLock<HashSet<Uuid>>,
+    // Protocol version each client negotiated in `hello`
+    versions: sync::RwLock<HashMap<Uuid, u32>>,
+    capabilities: sync::RwLock<HashMap<Uuid, Vec<Capability>>>,
+    journals: sync::Mutex<HashMap<Uuid, Journal>>,
     users: RwLock<HashMap<Uuid, User>>,
-    feed: RwLock<Feed>,
+    sessions: RwLock<HashMap<Uuid, Session>>,
+    typing: RwLock<HashMap<Uuid, Instant>>,
+    mutes: RwLock<HashMap<Uuid, Instant>>,
+    recent_posts: RwLock<HashMap<(Uuid, String), RecentPost>>,
+    name_bans: RwLock<HashMap<String, Instant>>,
+    address_bans: sync::RwLock<HashMap<IpAddr, Instant>>,
+    clients: RwLock<HashMap<Uuid, Uuid>>,
+    rooms: RwLock<HashMap<Uuid, Room>>,
+    storage: RwLock<Box<dyn Storage>>,
 }
 
 impl Hub {
     pub fn new(options: HubOptions) -> Self {
-        let (output_sender, _) = broadcast::channel(OUTPUT_CHANNEL_SIZE);
+        Self::with_storage(options, Box::new(MemoryStorage::new()))
+    }
+
+    pub fn with_storage(options: HubOptions, storage: Box<dyn Storage>) -> Self {
+        // Restore rooms created before a restart
+        let mut rooms: HashMap<Uuid, Room> = storage
+            .rooms()
+            .into_iter()
+            .map(|room| (room.id, room))
+            .collect();
+        rooms.insert(
+            DEFAULT_ROOM_ID,
+            Room::new(DEFAULT_ROOM_ID, DEFAULT_ROOM_NAME),
+        );
         Hub {
             alive_interval: options.alive_interval,
-            output_sender,
+            resume_window: options.resume_window,
+            typing_timeout: options.typing_timeout,
+            lag_policy: options.lag_policy,
+            dedupe_window: options.dedupe_window,
+            journal_capacity: options.journal_capacity,
+            retention: options.retention,
+            moderators: options.moderators,
+            reserved_names: options.reserved_names,
+            authenticator: options.authenticator,
+            max_message_body_length: options.max_message_body_length,
+            outbox_capacity: options.outbox_capacity,
+            rate_limiter: options.rate_limit.map(RateLimiter::new),
+            metrics: Metrics::new(),
+            running: AtomicBool::new(false),
+            processing_since: Default::default(),
+            last_processed_at: Default::default(),
+            outboxes: Default::default(),
+            addresses: Default::default(),
+            service_clients: Default::default(),
+            versions: Default::default(),
+            capabilities: Default::default(),
+            journals: Default::default(),
             users: Default::default(),
-            feed: Default::default(),
+            sessions: Default::default(),
+            typing: Default::default(),
+            mutes: Default::default(),
+            recent_posts: Default::default(),
+            name_bans: Default::default(),
+            address_bans: Default::default(),
+            clients: Default::default(),
+            rooms: RwLock::new(rooms),
+            storage: RwLock::new(storage),
         }
     }
 
     pub async fn run(&self, receiver: UnboundedReceiver<InputParcel>) {
         let ticking_alive = self.tick_alive();
-        let processing = receiver.for_each(|input_parcel| self.process(input_parcel));
+        let ticking_sessions = self.tick_sessions();
+        let ticking_typing = self.tick_typing();
+        let ticking_retention = self.tick_retention();
+        let processing = UnboundedReceiverStream::new(receiver)
+            .for_each(|input_parcel| self.process(input_parcel));
+        self.running.store(true, Ordering::SeqCst);
         tokio::select! {
             _ = ticking_alive => {},
+            _ = ticking_sessions => {},
+            _ = ticking_typing => {},
+            _ = ticking_retention => {},
             _ = processing => {},
         }
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn connect(&self, client_id: Uuid, address: Option<IpAddr>) -> Arc<Outbox> {
+        let outbox = Arc::new(Outbox::new(self.outbox_capacity, self.lag_policy));
+        if let Some(address) = address {
+            // Turn away banned addresses before they can send anything
+            if is_active(&mut *self.address_bans.write().unwrap(), &address) {
+                outbox.push(Output::Error(OutputError::Banned));
+                outbox.close(CloseReason::Banned);
+                return outbox;
+            }
+            self.addresses.write().unwrap().insert(client_id, address);
+        }
+        let mut outboxes = self.outboxes.write().unwrap();
+        outboxes.insert(client_id, outbox.clone());
+        self.metrics.connected_clients.set(outboxes.len() as i64);
+        outbox
+    }
+
+    /// Connects one of the server's own clients, which may join under a
+    /// reserved name.
+    pub fn connect_service(&self, client_id: Uuid) -> Arc<Outbox> {
+        self.service_clients.write().unwrap().insert(client_id);
+        self.connect(client_id, None)
+    }
+
+    /// Checks a client's join request before it is queued, reporting whether
+    /// it may go ahead. Verifying a password is slow on purpose, so this runs
+    /// on the client's task rather than holding up every other input.
+    pub async fn authenticate(&self, client_id: Uuid, input: &JoinInput) -> bool {
+        let authenticator = if let Some(authenticator) = &self.authenticator {
+            authenticator.clone()
+        } else {
+            return true;
+        };
+        // Every attempt counts against the rate limit, so that guessing
+        // passwords is as slow as anything else
+        if !self.check_rate_limit(client_id) {
+            return false;
+        }
+        let name = String::from(input.name.trim());
+        let credential = input.credential.clone();
+        let authenticated =
+            task::spawn_blocking(move || authenticator.authenticate(&name, credential.as_deref()))
+                .await
+                .unwrap_or(false);
+        if !authenticated {
+            self.reject(client_id, OutputError::Unauthorized);
+        }
+        authenticated
+    }
+
+    /// Whether users have to prove their names to join.
+    pub fn requires_authentication(&self) -> bool {
+        self.authenticator.is_some()
+    }
+
+    pub fn default_room_id(&self) -> Uuid {
+        DEFAULT_ROOM_ID
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub async fn health(&self) -> Health {
+        let stalled = self
+            .processing_since
+            .lock()
+            .unwrap()
+            .is_some_and(|since| since.elapsed() >= STALL_TIMEOUT);
+        // Storage held locked by a stuck input counts as unavailable
+        let storage_available =
+            match time::timeout(STORAGE_CHECK_TIMEOUT, self.storage.read()).await {
+                Ok(storage) => match storage.check() {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!("Storage check failed: {}", err);
+                        false
+                    }
+                },
+                Err(_) => false,
+            };
+        Health {
+            running: self.running.load(Ordering::SeqCst),
+            stalled,
+            last_processed_at: *self.last_processed_at.lock().unwrap(),
+            storage_available,
+            connected_clients: self.outboxes.read().unwrap().len(),
+        }
+    }
+
+    /// Page of a room's history for readers outside the feed, or `None` if
+    /// there is no such room.
+    pub async fn history(
+        &self,
+        room_id: Uuid,
+        before: Option<Uuid>,
+        limit: usize,
+    ) -> Option<HistoryOutput> {
+        if !self.rooms.read().await.contains_key(&room_id) {
+            return None;
+        }
+        // Every page holds at least one message, so that paging moves on
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE_LENGTH);
+        let (messages, cursor, pruned_until) = self.history_page(room_id, before, limit).await;
+        Some(HistoryOutput::new(room_id, messages, cursor, pruned_until))
+    }
+
+    pub async fn users(&self) -> Vec<UserOutput> {
+        let mut users: Vec<UserOutput> = self
+            .users
+            .read()
+            .await
+            .values()
+            .map(|user| UserOutput::new(user.id, &user.name))
+            .collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
     }
 
-    pub fn subscribe(&self) -> broadcast::Receiver<OutputParcel> {
-        self.output_sender.subscribe()
+    /// Answers a client with an error for something that never became an
+    /// input, such as a frame that could not be parsed.
+    pub fn reject(&self, client_id: Uuid, error: OutputError) {
+        self.send_error(client_id, error);
     }
 
-    pub async fn on_disconnect(&self, client_id: Uuid) {
-        // Remove user on disconnect
-        if self.users.write().await.remove(&client_id).is_some() {
-            self.send_ignored(client_id, Output::UserLeft(UserLeftOutput::new(client_id)))
-                .await;
+    pub async fn on_disconnect(&self, client_id: Uuid, close_frame: Option<CloseFrame>) {
+        if let Some(close_frame) = &close_frame {
+            info!(
+                "Client {} closed the connection: {} {}",
+                client_id, close_frame.code, close_frame.reason
+            );
+        }
+        self.remove_outbox(client_id);
+        self.addresses.write().unwrap().remove(&client_id);
+        self.service_clients.write().unwrap().remove(&client_id);
+        self.versions.write().unwrap().remove(&client_id);
+        let capabilities = self
+            .capabilities
+            .write()
+            .unwrap()
+            .remove(&client_id)
+            .unwrap_or_default();
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.remove(client_id);
+        }
+
+        let user_id = if let Some(user_id) = self.clients.write().await.remove(&client_id) {
+            user_id
+        } else {
+            return;
+        };
+
+        // Users that said goodbye are not coming back
+        let left = close_frame.is_some_and(|close_frame| close_frame.is_normal());
+        if self.resume_window.is_none() || left {
+            self.remove_user(user_id).await;
+            return;
+        }
+        // Keep the user around so they can resume within the window
+        if let Some(session) = self.sessions.write().await.get_mut(&user_id) {
+            if session.client_id == Some(client_id) {
+                session.client_id = None;
+                session.disconnected_at = Some(Instant::now());
+                session.capabilities = capabilities;
+            }
         }
     }
 
     async fn process(&self, input_parcel: InputParcel) {
+        // Inputs from clients that were turned away, kicked or are gone go nowhere
+        let connected = self
+            .outboxes
+            .read()
+            .unwrap()
+            .get(&input_parcel.client_id)
+            .is_some_and(|outbox| outbox.close_reason().is_none());
+        if !connected {
+            return;
+        }
+
+        let timer = self
+            .metrics
+            .input_latency
+            .with_label_values(&[input_parcel.input.name()])
+            .start_timer();
+        *self.processing_since.lock().unwrap() = Some(Instant::now());
+        // Only inputs that change something count against the rate limit, and
+        // joins already did when they were authenticated
+        let authenticated =
+            matches!(input_parcel.input, Input::Join(_)) && self.authenticator.is_some();
+        if !input_parcel.input.is_rate_limited()
+            || authenticated
+            || self.check_rate_limit(input_parcel.client_id)
+        {
+            self.dispatch(input_parcel).await;
+        }
+        timer.observe_duration();
+        *self.processing_since.lock().unwrap() = None;
+        *self.last_processed_at.lock().unwrap() = Some(Utc::now());
+    }
+
+    async fn dispatch(&self, input_parcel: InputParcel) {
         match input_parcel.input {
             Input::Join(input) => self.process_join(input_parcel.client_id, input).await,
+            Input::Resume(input) => self.process_resume(input_parcel.client_id, input).await,
             Input::Post(input) => self.process_post(input_parcel.client_id, input).await,
+            Input::CreateRoom(input) => {
+                self.process_create_room(input_parcel.client_id, input)
+                    .await
+            }
+            Input::JoinRoom(input) => self.process_join_room(input_parcel.client_id, input).await,
+            Input::LeaveRoom(input) => self.process_leave_room(input_parcel.client_id, input).await,
+            Input::ListRooms => self.process_list_rooms(input_parcel.client_id).await,
+            Input::DirectMessage(input) => {
+                self.process_direct_message(input_parcel.client_id, input)
+                    .await
+            }
+            Input::FetchHistory(input) => {
+                self.process_fetch_history(input_parcel.client_id, input)
+                    .await
+            }
+            Input::EditMessage(input) => {
+                self.process_edit_message(input_parcel.client_id, input)
+                    .await
+            }
+            Input::DeleteMessage(input) => {
+                self.process_delete_message(input_parcel.client_id, input)
+                    .await
+            }
+            Input::Typing(input) => self.process_typing(input_parcel.client_id, input).await,
+            Input::Kick(input) => self.process_kick(input_parcel.client_id, input).await,
+            Input::Mute(input) => self.process_mute(input_parcel.client_id, input).await,
+            Input::Ban(input) => self.process_ban(input_parcel.client_id, input).await,
+            Input::Hello(input) => self.process_hello(input_parcel.client_id, input),
+            Input::CatchUp(input) => self.process_catch_up(input_parcel.client_id, input).await,
+            Input::React(input) => {
+                self.process_reaction(input_parcel.client_id, input, true)
+                    .await
+            }
+            Input::Unreact(input) => {
+                self.process_reaction(input_parcel.client_id, input, false)
+                    .await
+            }
+            Input::FetchThread(input) => {
+                self.process_fetch_thread(input_parcel.client_id, input)
+                    .await
+            }
+        }
+    }
+
+    // Takes a token from the client's bucket, telling the client if it is
+    // sending too fast
+    fn check_rate_limit(&self, client_id: Uuid) -> bool {
+        let rate_limiter = if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter
+        } else {
+            return true;
+        };
+        match rate_limiter.check(client_id) {
+            Ok(()) => true,
+            Err(retry_after) => {
+                self.send_error(
+                    client_id,
+                    OutputError::RateLimited {
+                        retry_after_ms: retry_after.as_millis() as u64,
+                    },
+                );
+                false
+            }
         }
     }
 
     async fn process_join(&self, client_id: Uuid, input: JoinInput) {
         let user_name = input.name.trim();
 
+        // Check if the client already speaks for a user
+        if self.clients.read().await.contains_key(&client_id) {
+            self.send_error(client_id, OutputError::AlreadyJoined);
+            return;
+        }
+
         // Check if user's name is taken
         if self
             .users
@@ -89,212 +501,3537 @@ impl Hub {
             return;
         }
 
+        // Check if user's name is kept for the server's own clients
+        if self.reserved_names.iter().any(|name| name == user_name)
+            && !self.service_clients.read().unwrap().contains(&client_id)
+        {
+            self.send_error(client_id, OutputError::NameTaken);
+            return;
+        }
+
         // Validate user name
         if !USER_NAME_REGEX.is_match(user_name) {
             self.send_error(client_id, OutputError::InvalidName);
             return;
         }
 
-        let user = User::new(client_id, user_name);
-        self.users.write().await.insert(client_id, user.clone());
+        // Check if user's name is banned
+        if is_active(&mut *self.name_bans.write().await, &String::from(user_name)) {
+            self.send_error(client_id, OutputError::Banned);
+            return;
+        }
 
-        // Report success to user
-        let user_output = UserOutput::new(client_id, user_name);
-        let other_users = self
-            .users
-            .read()
+        let role = if self.moderators.iter().any(|name| name == user_name) {
+            Role::Moderator
+        } else {
+            Role::Member
+        };
+        let user = User::with_role(client_id, user_name, role);
+        let token = Uuid::new_v4().to_simple().to_string();
+        {
+            let mut users = self.users.write().await;
+            users.insert(user.id, user.clone());
+            self.metrics.joined_users.set(users.len() as i64);
+        }
+        self.sessions
+            .write()
             .await
-            .values()
-            .filter_map(|user| {
-                if user.id != client_id {
-                    Some(UserOutput::new(user.id, &user.name))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let messages = self
-            .feed
+            .insert(user.id, Session::new(user.id, &token, client_id));
+        self.clients.write().await.insert(client_id, user.id);
+
+        // Place user in the default room
+        self.enter_room(&user, client_id, DEFAULT_ROOM_ID, Some(token))
+            .await;
+    }
+
+    async fn process_resume(&self, client_id: Uuid, input: ResumeInput) {
+        // Check if the client already speaks for a user, whose session would
+        // otherwise be left pointing at it
+        if self.clients.read().await.contains_key(&client_id) {
+            self.send_error(client_id, OutputError::AlreadyJoined);
+            return;
+        }
+
+        let (user_id, replaced_client_id) = {
+            let mut sessions = self.sessions.write().await;
+            let session = if let Some(session) = sessions
+                .values_mut()
+                .find(|session| session.token == input.token)
+            {
+                session
+            } else {
+                self.send_error(client_id, OutputError::InvalidSession);
+                return;
+            };
+            let replaced_client_id = session.client_id.replace(client_id);
+            session.disconnected_at = None;
+            (session.user_id, replaced_client_id)
+        };
+        {
+            let mut clients = self.clients.write().await;
+            // A stale connection that is still open loses the session
+            if let Some(replaced_client_id) = replaced_client_id {
+                clients.remove(&replaced_client_id);
+            }
+            clients.insert(client_id, user_id);
+        }
+
+        let user = if let Some(user) = self.users.read().await.get(&user_id) {
+            UserOutput::new(user.id, &user.name)
+        } else {
+            return;
+        };
+        let mut rooms: Vec<RoomOutput> = self
+            .rooms
             .read()
             .await
-            .messages_iter()
-            .map(|message| {
-                MessageOutput::new(
-                    message.id,
-                    UserOutput::new(message.user.id, &message.user.name),
-                    &message.body,
-                    message.created_at,
-                )
-            })
+            .values()
+            .filter(|room| room.users.contains(&user_id))
+            .map(|room| RoomOutput::new(room.id, &room.name))
             .collect();
-        self.send_targeted(
-            client_id,
-            Output::Joined(JoinedOutput::new(
-                user_output.clone(),
-                other_users,
-                messages,
-            )),
-        );
-        // Notify others that someone joined
-        self.send_ignored(
-            client_id,
-            Output::UserJoined(UserJoinedOutput::new(user_output)),
-        )
-        .await;
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        self.send_targeted(client_id, Output::Resumed(ResumedOutput::new(user, rooms)));
     }
 
-    async fn process_post(&self, client_id: Uuid, input: PostInput) {
+    async fn process_post(&self, client_id: Uuid, mut input: PostInput) {
+        // The first version knew only the default room
+        if self.version(client_id) == MIN_PROTOCOL_VERSION {
+            input.room_id = DEFAULT_ROOM_ID;
+        }
+
         // Verify that user exists
-        let user = if let Some(user) = self.users.read().await.get(&client_id) {
-            user.clone()
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
         } else {
             self.send_error(client_id, OutputError::NotJoined);
             return;
         };
 
+        // Check if the post is a retry of one that already went through
+        if let Some(nonce) = &input.nonce {
+            if nonce.is_empty() || nonce.len() > MAX_NONCE_LENGTH {
+                self.send_error(client_id, OutputError::MalformedInput);
+                return;
+            }
+            if let Some(recent_post) = self.recent_post(user.id, nonce).await {
+                self.repeat_post(client_id, recent_post, input.nonce).await;
+                return;
+            }
+        }
+
+        // Check if user is allowed to speak
+        if is_active(&mut *self.mutes.write().await, &user.id) {
+            self.send_error(client_id, OutputError::Muted);
+            return;
+        }
+
         // Validate message body
-        if input.body.is_empty() || input.body.len() > MAX_MESSAGE_BODY_LENGTH {
+        if input.body.is_empty() || input.body.len() > self.max_message_body_length {
             self.send_error(client_id, OutputError::InvalidMessageBody);
             return;
         }
 
-        let message = Message::new(Uuid::new_v4(), user.clone(), &input.body, Utc::now());
-        self.feed.write().await.add_message(message.clone());
+        // Verify that user is a member of the room
+        if !self.verify_member(client_id, user.id, input.room_id).await {
+            return;
+        }
 
-        let message_output = MessageOutput::new(
-            message.id,
-            UserOutput::new(user.id, &user.name),
-            &message.body,
-            message.created_at,
-        );
+        let mut message = Message::new(Uuid::new_v4(), user.clone(), &input.body, Utc::now());
+        let mut storage = self.storage.write().await;
+        // Check that the message replied to is still there, a reply to a reply
+        // joins the same thread
+        if let Some(reply_to) = input.reply_to {
+            match storage.message(input.room_id, reply_to) {
+                Some(parent) if !parent.deleted => {
+                    let thread_root = parent.thread_root.unwrap_or(parent.id);
+                    message = message.in_thread(reply_to, thread_root);
+                }
+                _ => {
+                    drop(storage);
+                    self.send_error(client_id, OutputError::MessageNotFound);
+                    return;
+                }
+            }
+        }
+        let stored = storage.add_message(input.room_id, message.clone());
+        // Count the replies of a thread's first message, unless it is gone
+        let reply_count = message
+            .thread_root
+            .and_then(|root_id| storage.message(input.room_id, root_id))
+            .map(|root| root.reply_count);
+        drop(storage);
+        if let Err(err) = stored {
+            self.send_internal_error(client_id, err);
+            return;
+        }
+
+        self.metrics.messages_posted.inc();
+
+        if let (Some(nonce), Some(_)) = (&input.nonce, self.dedupe_window) {
+            self.recent_posts.write().await.insert(
+                (user.id, nonce.clone()),
+                RecentPost {
+                    room_id: input.room_id,
+                    message_id: message.id,
+                    posted_at: Instant::now(),
+                },
+            );
+        }
+
+        let message_output = message_output(&message);
         // Report post status
         self.send_targeted(
             client_id,
-            Output::Posted(PostedOutput::new(message_output.clone())),
+            Output::Posted(PostedOutput::new(
+                input.room_id,
+                message_output.clone(),
+                input.nonce,
+            )),
         );
-        // Notify everybody about new message
+        // Notify everybody in the room about new message
+        let user_posted = UserPostedOutput::new(input.room_id, message_output);
+        match message.thread_root {
+            Some(root_id) => {
+                self.send_room(input.room_id, user.id, Output::UserReplied(user_posted))
+                    .await;
+                // Clients without threads still get to show how many replies there are
+                if let Some(reply_count) = reply_count {
+                    self.send_room(
+                        input.room_id,
+                        user.id,
+                        Output::RepliesChanged(RepliesChangedOutput::new(
+                            input.room_id,
+                            root_id,
+                            reply_count,
+                        )),
+                    )
+                    .await;
+                }
+            }
+            None => {
+                self.send_room(input.room_id, user.id, Output::UserPosted(user_posted))
+                    .await
+            }
+        }
+        // Posting ends whatever the user was typing
+        self.stop_typing(user.id).await;
+    }
+
+    // Finds the post a user made with this nonce within the dedupe window
+    async fn recent_post(&self, user_id: Uuid, nonce: &str) -> Option<RecentPost> {
+        let dedupe_window = self.dedupe_window?;
+        let mut recent_posts = self.recent_posts.write().await;
+        recent_posts.retain(|_, recent_post| recent_post.posted_at.elapsed() < dedupe_window);
+        recent_posts.get(&(user_id, String::from(nonce))).copied()
+    }
+
+    // Reports a retried post as posted again, without telling anyone else
+    async fn repeat_post(&self, client_id: Uuid, recent_post: RecentPost, nonce: Option<String>) {
+        let message = self
+            .storage
+            .read()
+            .await
+            .message(recent_post.room_id, recent_post.message_id);
+        match message {
+            Some(message) => self.send_targeted(
+                client_id,
+                Output::Posted(PostedOutput::new(
+                    recent_post.room_id,
+                    message_output(&message),
+                    nonce,
+                )),
+            ),
+            // Deleted since it was posted
+            None => self.send_error(client_id, OutputError::MessageNotFound),
+        }
+    }
+
+    async fn process_create_room(&self, client_id: Uuid, input: CreateRoomInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+
+        let room_name = input.name.trim();
+
+        // Validate room name
+        if !ROOM_NAME_REGEX.is_match(room_name) {
+            self.send_error(client_id, OutputError::InvalidRoomName);
+            return;
+        }
+
+        let room = Room::new(Uuid::new_v4(), room_name);
+        let room_output = RoomOutput::new(room.id, &room.name);
+        {
+            let mut rooms = self.rooms.write().await;
+            // Check if room's name is taken
+            if rooms.values().any(|room| room.name == room_name) {
+                self.send_error(client_id, OutputError::RoomNameTaken);
+                return;
+            }
+            if let Err(err) = self.storage.write().await.add_room(&room) {
+                self.send_internal_error(client_id, err);
+                return;
+            }
+            rooms.insert(room.id, room);
+        }
+
+        // Notify others that a new room is available
         self.send_ignored(
-            client_id,
-            Output::UserPosted(UserPostedOutput::new(message_output)),
+            user.id,
+            Output::RoomCreated(RoomCreatedOutput::new(room_output.clone())),
         )
         .await;
+        // Place the creator in the new room
+        self.enter_room(&user, client_id, room_output.id, None)
+            .await;
     }
 
-    async fn tick_alive(&self) {
-        let alive_interval = if let Some(alive_interval) = self.alive_interval {
-            alive_interval
+    async fn process_join_room(&self, client_id: Uuid, input: JoinRoomInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
         } else {
+            self.send_error(client_id, OutputError::NotJoined);
             return;
         };
-        loop {
-            time::delay_for(alive_interval).await;
-            self.send(Output::Alive).await;
-        }
+        self.enter_room(&user, client_id, input.room_id, None).await;
     }
 
-    async fn send(&self, output: Output) {
-        if self.output_sender.receiver_count() == 0 {
+    async fn process_leave_room(&self, client_id: Uuid, input: LeaveRoomInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
             return;
-        }
-        self.users.read().await.keys().for_each(|user_id| {
-            self.output_sender
-                .send(OutputParcel::new(*user_id, output.clone()))
-                .unwrap();
-        });
-    }
+        };
 
-    fn send_targeted(&self, client_id: Uuid, output: Output) {
-        if self.output_sender.receiver_count() > 0 {
-            self.output_sender
-                .send(OutputParcel::new(client_id, output))
-                .unwrap();
+        match self.rooms.write().await.get_mut(&input.room_id) {
+            Some(room) => {
+                if !room.users.remove(&user.id) {
+                    self.send_error(client_id, OutputError::NotInRoom);
+                    return;
+                }
+            }
+            None => {
+                self.send_error(client_id, OutputError::RoomNotFound);
+                return;
+            }
         }
+
+        // Report success to user
+        self.send_targeted(client_id, Output::Left(LeftOutput::new(input.room_id)));
+        // Notify others in the room that someone left
+        self.send_room(
+            input.room_id,
+            user.id,
+            Output::UserLeft(UserLeftOutput::new(input.room_id, user.id)),
+        )
+        .await;
     }
 
-    async fn send_ignored(&self, ignored_client_id: Uuid, output: Output) {
-        if self.output_sender.receiver_count() == 0 {
+    async fn process_list_rooms(&self, client_id: Uuid) {
+        if self.get_user(client_id).await.is_none() {
+            self.send_error(client_id, OutputError::NotJoined);
             return;
         }
-        self.users
+
+        let mut rooms: Vec<RoomOutput> = self
+            .rooms
             .read()
             .await
             .values()
-            .filter(|user| user.id != ignored_client_id)
-            .for_each(|user| {
-                self.output_sender
-                    .send(OutputParcel::new(user.id, output.clone()))
-                    .unwrap();
-            });
+            .map(|room| RoomOutput::new(room.id, &room.name))
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        self.send_targeted(client_id, Output::Rooms(RoomsOutput::new(rooms)));
     }
 
-    fn send_error(&self, client_id: Uuid, error: OutputError) {
-        self.send_targeted(client_id, Output::Error(error));
-    }
-}
+    async fn process_direct_message(&self, client_id: Uuid, input: DirectMessageInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
 
-impl Default for Hub {
-    fn default() -> Self {
-        Self::new(HubOptions::default())
-    }
-}
+        // Verify that recipient is still around
+        if !self.users.read().await.contains_key(&input.to) {
+            self.send_error(client_id, OutputError::UnknownRecipient);
+            return;
+        }
 
-#[cfg(test)]
-mod tests {
-    use tokio::runtime::Runtime;
-    use tokio::sync::mpsc;
-    use uuid::Uuid;
+        // Check if user is allowed to speak
+        if is_active(&mut *self.mutes.write().await, &user.id) {
+            self.send_error(client_id, OutputError::Muted);
+            return;
+        }
 
-    use crate::hub::{Hub, HubOptions};
-    use crate::proto::{Input, InputParcel, JoinInput, Output, PostInput};
+        // Validate message body
+        if input.body.is_empty() || input.body.len() > self.max_message_body_length {
+            self.send_error(client_id, OutputError::InvalidMessageBody);
+            return;
+        }
 
-    #[test]
-    fn join_and_post() {
-        let hub = Hub::new(HubOptions::default());
-        let (sender, receiver) = mpsc::unbounded_channel();
-        let mut subscription = hub.subscribe();
+        let message = Message::new(Uuid::new_v4(), user.clone(), &input.body, Utc::now());
+        let stored = self
+            .storage
+            .write()
+            .await
+            .add_message(conversation_id(user.id, input.to), message.clone());
+        if let Err(err) = stored {
+            self.send_internal_error(client_id, err);
+            return;
+        }
 
-        let mut rt = Runtime::new().unwrap();
-        rt.block_on(async move {
-            let case = async {
-                let client_id = Uuid::new_v4();
+        let output =
+            Output::DirectMessage(DirectMessageOutput::new(input.to, message_output(&message)));
+        // Deliver to both participants only
+        self.send_targeted(client_id, output.clone());
+        if input.to != user.id {
+            self.send_user(input.to, output).await;
+        }
+    }
 
-                // Join
-                sender
-                    .send(InputParcel::new(
-                        client_id,
-                        Input::Join(JoinInput {
-                            name: String::from("John"),
-                        }),
-                    ))
-                    .unwrap();
-                let output = subscription.recv().await.unwrap().output;
+    async fn process_fetch_history(&self, client_id: Uuid, input: FetchHistoryInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+
+        // Only members may read a room's history
+        if !self.verify_member(client_id, user.id, input.room_id).await {
+            return;
+        }
+
+        // Every page holds at least one message, so that paging moves on
+        let limit = input.limit.clamp(1, MAX_HISTORY_PAGE_LENGTH);
+        let (messages, cursor, pruned_until) =
+            self.history_page(input.room_id, input.before, limit).await;
+        self.send_targeted(
+            client_id,
+            Output::History(HistoryOutput::new(
+                input.room_id,
+                messages,
+                cursor,
+                pruned_until,
+            )),
+        );
+    }
+
+    async fn process_fetch_thread(&self, client_id: Uuid, input: FetchThreadInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+
+        // Only look in rooms the user is a member of
+        let room_ids: Vec<Uuid> = self
+            .rooms
+            .read()
+            .await
+            .values()
+            .filter(|room| room.users.contains(&user.id))
+            .map(|room| room.id)
+            .collect();
+        let storage = self.storage.read().await;
+        let found = room_ids.into_iter().find_map(|room_id| {
+            storage
+                .message(room_id, input.message_id)
+                .map(|message| (room_id, message))
+        });
+        let (room_id, message) = if let Some(found) = found {
+            found
+        } else {
+            drop(storage);
+            self.send_error(client_id, OutputError::MessageNotFound);
+            return;
+        };
+
+        let root_id = message.thread_root.unwrap_or(message.id);
+        let root = storage
+            .message(room_id, root_id)
+            .map(|root| message_output(&root));
+        let replies = storage
+            .replies(room_id, root_id)
+            .iter()
+            .map(message_output)
+            .collect();
+        drop(storage);
+        self.send_targeted(
+            client_id,
+            Output::Thread(ThreadOutput::new(room_id, root_id, root, replies)),
+        );
+    }
+
+    async fn process_edit_message(&self, client_id: Uuid, input: EditMessageInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+        if !self.verify_member(client_id, user.id, input.room_id).await
+            || !self
+                .verify_author(client_id, user.id, input.room_id, input.message_id)
+                .await
+        {
+            return;
+        }
+
+        // Validate message body
+        if input.body.is_empty() || input.body.len() > self.max_message_body_length {
+            self.send_error(client_id, OutputError::InvalidMessageBody);
+            return;
+        }
+
+        let message = {
+            let mut storage = self.storage.write().await;
+            let edited =
+                storage.edit_message(input.room_id, input.message_id, &input.body, Utc::now());
+            if let Err(err) = edited {
+                self.send_internal_error(client_id, err);
+                return;
+            }
+            storage.message(input.room_id, input.message_id)
+        };
+
+        if let Some(message) = message {
+            let output = Output::MessageEdited(MessageEditedOutput::new(
+                input.room_id,
+                message_output(&message),
+            ));
+            // Notify everybody in the room, including the author
+            self.send_targeted(client_id, output.clone());
+            self.send_room(input.room_id, user.id, output).await;
+        }
+    }
+
+    async fn process_delete_message(&self, client_id: Uuid, input: DeleteMessageInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+        if !self.verify_member(client_id, user.id, input.room_id).await
+            || !self
+                .verify_author(client_id, user.id, input.room_id, input.message_id)
+                .await
+        {
+            return;
+        }
+
+        let replies_changed = {
+            let mut storage = self.storage.write().await;
+            if let Err(err) = storage.delete_message(input.room_id, input.message_id) {
+                drop(storage);
+                self.send_internal_error(client_id, err);
+                return;
+            }
+            // A deleted reply leaves its thread with one reply fewer
+            storage
+                .message(input.room_id, input.message_id)
+                .and_then(|message| message.thread_root)
+                .and_then(|root_id| storage.message(input.room_id, root_id))
+                .map(|root| RepliesChangedOutput::new(input.room_id, root.id, root.reply_count))
+        };
+
+        let output =
+            Output::MessageDeleted(MessageDeletedOutput::new(input.room_id, input.message_id));
+        // Notify everybody in the room, including the author
+        self.send_targeted(client_id, output.clone());
+        self.send_room(input.room_id, user.id, output).await;
+        if let Some(replies_changed) = replies_changed {
+            let output = Output::RepliesChanged(replies_changed);
+            self.send_targeted(client_id, output.clone());
+            self.send_room(input.room_id, user.id, output).await;
+        }
+    }
+
+    async fn process_reaction(&self, client_id: Uuid, input: ReactInput, added: bool) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+        if !self.verify_member(client_id, user.id, input.room_id).await {
+            return;
+        }
+
+        // Validate emoji
+        if !is_emoji(&input.emoji) {
+            self.send_error(client_id, OutputError::InvalidEmoji);
+            return;
+        }
+
+        let mut storage = self.storage.write().await;
+        let message = match storage.message(input.room_id, input.message_id) {
+            Some(message) if !message.deleted => message,
+            _ => {
+                drop(storage);
+                self.send_error(client_id, OutputError::MessageNotFound);
+                return;
+            }
+        };
+        let reactions = message.reactions_by_emoji();
+        let reacted = reactions
+            .get(input.emoji.as_str())
+            .is_some_and(|user_ids| user_ids.contains(&user.id));
+        // Nothing to tell anyone else, but the client still learns where things stand
+        if reacted == added {
+            drop(storage);
+            self.send_targeted(
+                client_id,
+                Output::ReactionsChanged(ReactionsChangedOutput::new(
+                    input.room_id,
+                    input.message_id,
+                    reaction_outputs(&message),
+                )),
+            );
+            return;
+        }
+        // Check that the message has room for another kind of reaction
+        if added
+            && !reactions.contains_key(input.emoji.as_str())
+            && reactions.len() >= MAX_MESSAGE_REACTIONS
+        {
+            drop(storage);
+            self.send_error(client_id, OutputError::TooManyReactions);
+            return;
+        }
+
+        let stored = storage.react(
+            input.room_id,
+            input.message_id,
+            user.id,
+            &input.emoji,
+            added,
+        );
+        let message = storage.message(input.room_id, input.message_id);
+        drop(storage);
+        if let Err(err) = stored {
+            self.send_internal_error(client_id, err);
+            return;
+        }
+
+        if let Some(message) = message {
+            let output = Output::ReactionsChanged(ReactionsChangedOutput::new(
+                input.room_id,
+                input.message_id,
+                reaction_outputs(&message),
+            ));
+            // Notify everybody in the room, including the user that reacted
+            self.send_targeted(client_id, output.clone());
+            self.send_room(input.room_id, user.id, output).await;
+        }
+    }
+
+    async fn process_typing(&self, client_id: Uuid, input: TypingInput) {
+        // Verify that user exists
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+
+        if !input.active {
+            self.stop_typing(user.id).await;
+            return;
+        }
+        // Repeated updates only extend the expiry, so keystrokes don't flood everyone else
+        let started = self
+            .typing
+            .write()
+            .await
+            .insert(user.id, Instant::now())
+            .is_none();
+        if started {
+            self.send_ignored(
+                user.id,
+                Output::UserTyping(UserTypingOutput::new(user.id, true)),
+            )
+            .await;
+        }
+    }
+
+    async fn process_kick(&self, client_id: Uuid, input: KickInput) {
+        let moderator = if let Some(moderator) = self.get_moderator(client_id).await {
+            moderator
+        } else {
+            return;
+        };
+
+        let user = if let Some(user) = self.users.read().await.get(&input.user_id).cloned() {
+            user
+        } else {
+            self.send_error(client_id, OutputError::UserNotFound);
+            return;
+        };
+
+        let user_id = user.id;
+        self.send_moderated(ModerationAction::Kick, &moderator, &[user], None)
+            .await;
+        self.disconnect_user(user_id, CloseReason::Kicked).await;
+    }
+
+    async fn process_mute(&self, client_id: Uuid, input: MuteInput) {
+        let moderator = if let Some(moderator) = self.get_moderator(client_id).await {
+            moderator
+        } else {
+            return;
+        };
+
+        let user = if let Some(user) = self.users.read().await.get(&input.user_id).cloned() {
+            user
+        } else {
+            self.send_error(client_id, OutputError::UserNotFound);
+            return;
+        };
+
+        // Validate duration
+        let duration = Duration::from_secs(input.duration_secs);
+        if duration > MAX_MODERATION_DURATION {
+            self.send_error(client_id, OutputError::MalformedInput);
+            return;
+        }
+
+        self.mutes
+            .write()
+            .await
+            .insert(user.id, Instant::now() + duration);
+        self.send_moderated(ModerationAction::Mute, &moderator, &[user], Some(duration))
+            .await;
+    }
+
+    async fn process_ban(&self, client_id: Uuid, input: BanInput) {
+        let moderator = if let Some(moderator) = self.get_moderator(client_id).await {
+            moderator
+        } else {
+            return;
+        };
+
+        // Validate duration
+        let duration = Duration::from_secs(input.duration_secs);
+        if duration > MAX_MODERATION_DURATION {
+            self.send_error(client_id, OutputError::MalformedInput);
+            return;
+        }
+
+        let until = Instant::now() + duration;
+        // Collect everyone currently connected under the banned name or address
+        let users: Vec<User> = match input.target {
+            BanTarget::Name(name) => {
+                let name = name.trim();
+                self.name_bans
+                    .write()
+                    .await
+                    .insert(String::from(name), until);
+                self.users
+                    .read()
+                    .await
+                    .values()
+                    .filter(|user| user.name == name)
+                    .cloned()
+                    .collect()
+            }
+            BanTarget::Address(address) => {
+                self.address_bans.write().unwrap().insert(address, until);
+                let client_ids: Vec<Uuid> = self
+                    .addresses
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, client_address)| **client_address == address)
+                    .map(|(client_id, _)| *client_id)
+                    .collect();
+                let clients = self.clients.read().await;
+                let users = self.users.read().await;
+                client_ids
+                    .iter()
+                    .filter_map(|client_id| users.get(clients.get(client_id)?))
+                    .cloned()
+                    .collect()
+            }
+        };
+
+        self.send_moderated(ModerationAction::Ban, &moderator, &users, Some(duration))
+            .await;
+        for user in users {
+            self.disconnect_user(user.id, CloseReason::Banned).await;
+        }
+    }
+
+    fn process_hello(&self, client_id: Uuid, input: HelloInput) {
+        // Check that the client speaks a version the server still understands
+        if input.version < MIN_PROTOCOL_VERSION {
+            self.send_error(
+                client_id,
+                OutputError::UnsupportedVersion {
+                    min_version: MIN_PROTOCOL_VERSION,
+                    max_version: PROTOCOL_VERSION,
+                },
+            );
+            return;
+        }
+
+        // Speak the newest version both sides know
+        let version = input.version.min(PROTOCOL_VERSION);
+        // Offer only what the version knows about
+        let offered: Vec<Capability> = Capability::ALL
+            .iter()
+            .copied()
+            .filter(|capability| capability.since() <= version)
+            .collect();
+        let capabilities = input
+            .capabilities
+            .iter()
+            .filter_map(|name| Capability::from_name(name))
+            .filter(|capability| offered.contains(capability))
+            .collect();
+        self.versions.write().unwrap().insert(client_id, version);
+        self.capabilities
+            .write()
+            .unwrap()
+            .insert(client_id, capabilities);
+        self.send_targeted(client_id, Output::Hello(HelloOutput::new(version, offered)));
+    }
+
+    async fn process_catch_up(&self, client_id: Uuid, input: CatchUpInput) {
+        let user = if let Some(user) = self.get_user(client_id).await {
+            user
+        } else {
+            self.send_error(client_id, OutputError::NotJoined);
+            return;
+        };
+
+        let (parcels, last_seq) = {
+            let mut journals = self.journals.lock().unwrap();
+            let journal = journals
+                .entry(user.id)
+                .or_insert_with(|| Journal::new(self.journal_capacity));
+            (journal.since(input.since_seq), journal.last_seq())
+        };
+        // Check that nothing the client missed was forgotten
+        let parcels = if let Some(parcels) = parcels {
+            parcels
+        } else {
+            self.send_error(client_id, OutputError::CatchUpUnavailable { last_seq });
+            return;
+        };
+
+        // Replay only what this client would have been sent, the way it would
+        // have been sent
+        let numbered = self.version(client_id) >= SEQ_PROTOCOL_VERSION;
+        if let Some(outbox) = self.outboxes.read().unwrap().get(&client_id) {
+            let capabilities = self.capabilities.read().unwrap();
+            parcels
+                .into_iter()
+                .filter(|parcel| understands(&capabilities, &client_id, &parcel.output))
+                .map(|parcel| {
+                    if numbered {
+                        parcel
+                    } else {
+                        OutputParcel::new(None, parcel.output)
+                    }
+                })
+                .for_each(|parcel| self.deliver(outbox, parcel));
+        }
+        self.send_targeted(client_id, Output::CaughtUp(CaughtUpOutput::new(last_seq)));
+    }
+
+    async fn enter_room(
+        &self,
+        user: &User,
+        client_id: Uuid,
+        room_id: Uuid,
+        session_token: Option<String>,
+    ) {
+        let (room_output, other_user_ids) = {
+            let mut rooms = self.rooms.write().await;
+            let room = if let Some(room) = rooms.get_mut(&room_id) {
+                room
+            } else {
+                self.send_error(client_id, OutputError::RoomNotFound);
+                return;
+            };
+            room.users.insert(user.id);
+
+            let other_user_ids: Vec<Uuid> = room
+                .users
+                .iter()
+                .filter(|id| **id != user.id)
+                .copied()
+                .collect();
+            (RoomOutput::new(room.id, &room.name), other_user_ids)
+        };
+        let (messages, cursor, pruned_until) =
+            self.history_page(room_id, None, JOIN_HISTORY_LENGTH).await;
+
+        // Report success to user
+        let user_output = UserOutput::new(user.id, &user.name);
+        let other_users = {
+            let users = self.users.read().await;
+            other_user_ids
+                .iter()
+                .filter_map(|id| users.get(id))
+                .map(|user| UserOutput::new(user.id, &user.name))
+                .collect()
+        };
+        self.send_targeted(
+            client_id,
+            Output::Joined(JoinedOutput::new(
+                room_output,
+                user_output.clone(),
+                other_users,
+                messages,
+                cursor,
+                pruned_until,
+                session_token,
+            )),
+        );
+        // Notify others in the room that someone joined
+        self.send_room(
+            room_id,
+            user.id,
+            Output::UserJoined(UserJoinedOutput::new(room_id, user_output)),
+        )
+        .await;
+    }
+
+    async fn remove_user(&self, user_id: Uuid) {
+        {
+            let mut users = self.users.write().await;
+            users.remove(&user_id);
+            self.metrics.joined_users.set(users.len() as i64);
+        }
+        self.sessions.write().await.remove(&user_id);
+        self.stop_typing(user_id).await;
+        self.mutes.write().await.remove(&user_id);
+        self.recent_posts
+            .write()
+            .await
+            .retain(|(author_id, _), _| *author_id != user_id);
+        self.journals.lock().unwrap().remove(&user_id);
+        // Leave every room the user was in
+        let left_room_ids: Vec<Uuid> = self
+            .rooms
+            .write()
+            .await
+            .values_mut()
+            .filter_map(|room| {
+                if room.users.remove(&user_id) {
+                    Some(room.id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for room_id in left_room_ids {
+            self.send_room(
+                room_id,
+                user_id,
+                Output::UserLeft(UserLeftOutput::new(room_id, user_id)),
+            )
+            .await;
+        }
+    }
+
+    async fn disconnect_user(&self, user_id: Uuid, reason: CloseReason) {
+        let client_id = self
+            .sessions
+            .read()
+            .await
+            .get(&user_id)
+            .and_then(|session| session.client_id);
+        if let Some(client_id) = client_id {
+            // Forget the connection first, so that it cannot be resumed
+            self.clients.write().await.remove(&client_id);
+            if let Some(outbox) = self.remove_outbox(client_id) {
+                outbox.close(reason);
+            }
+        }
+        self.remove_user(user_id).await;
+    }
+
+    async fn send_moderated(
+        &self,
+        action: ModerationAction,
+        moderator: &User,
+        users: &[User],
+        duration: Option<Duration>,
+    ) {
+        let until = duration
+            .and_then(|duration| chrono::Duration::from_std(duration).ok())
+            .map(|duration| Utc::now() + duration);
+        self.send(Output::Moderated(ModeratedOutput::new(
+            action,
+            UserOutput::new(moderator.id, &moderator.name),
+            users
+                .iter()
+                .map(|user| UserOutput::new(user.id, &user.name))
+                .collect(),
+            until,
+        )))
+        .await;
+    }
+
+    async fn stop_typing(&self, user_id: Uuid) {
+        if self.typing.write().await.remove(&user_id).is_some() {
+            self.send_ignored(
+                user_id,
+                Output::UserTyping(UserTypingOutput::new(user_id, false)),
+            )
+            .await;
+        }
+    }
+
+    async fn history_page(
+        &self,
+        feed_id: Uuid,
+        before: Option<Uuid>,
+        limit: usize,
+    ) -> (Vec<MessageOutput>, Option<Uuid>, Option<DateTime<Utc>>) {
+        let storage = self.storage.read().await;
+        // Fetch one extra message to tell whether there is anything older
+        let mut messages = storage.messages(feed_id, before, limit + 1);
+        let cursor = if messages.len() > limit {
+            messages.remove(0);
+            messages.first().map(|message| message.id)
+        } else {
+            None
+        };
+        // The last page tells whether history goes back further than what is kept
+        let pruned_until = if cursor.is_none() {
+            storage.pruned_until(feed_id)
+        } else {
+            None
+        };
+        (
+            messages.iter().map(message_output).collect(),
+            cursor,
+            pruned_until,
+        )
+    }
+
+    async fn verify_member(&self, client_id: Uuid, user_id: Uuid, room_id: Uuid) -> bool {
+        match self.rooms.read().await.get(&room_id) {
+            Some(room) => {
+                if room.users.contains(&user_id) {
+                    true
+                } else {
+                    self.send_error(client_id, OutputError::NotInRoom);
+                    false
+                }
+            }
+            None => {
+                self.send_error(client_id, OutputError::RoomNotFound);
+                false
+            }
+        }
+    }
+
+    async fn verify_author(
+        &self,
+        client_id: Uuid,
+        user_id: Uuid,
+        room_id: Uuid,
+        message_id: Uuid,
+    ) -> bool {
+        match self.storage.read().await.message(room_id, message_id) {
+            Some(message) if !message.deleted => {
+                if message.user.id == user_id {
+                    true
+                } else {
+                    self.send_error(client_id, OutputError::NotMessageAuthor);
+                    false
+                }
+            }
+            _ => {
+                self.send_error(client_id, OutputError::MessageNotFound);
+                false
+            }
+        }
+    }
+
+    async fn get_user(&self, client_id: Uuid) -> Option<User> {
+        let user_id = *self.clients.read().await.get(&client_id)?;
+        self.users.read().await.get(&user_id).cloned()
+    }
+
+    async fn get_moderator(&self, client_id: Uuid) -> Option<User> {
+        match self.get_user(client_id).await {
+            Some(user) if user.is_moderator() => Some(user),
+            Some(_) => {
+                self.send_error(client_id, OutputError::NotModerator);
+                None
+            }
+            None => {
+                self.send_error(client_id, OutputError::NotJoined);
+                None
+            }
+        }
+    }
+
+    async fn tick_alive(&self) {
+        let alive_interval = if let Some(alive_interval) = self.alive_interval {
+            alive_interval
+        } else {
+            // Never finish, so that `run` keeps processing inputs
+            return future::pending().await;
+        };
+        loop {
+            time::sleep(alive_interval).await;
+            // Keep-alives are nothing to catch up on, so they skip the journals
+            let sessions = self.sessions.read().await;
+            let outboxes = self.outboxes.read().unwrap();
+            for client_id in sessions.values().filter_map(|session| session.client_id) {
+                if let Some(outbox) = outboxes.get(&client_id) {
+                    self.deliver(outbox, OutputParcel::new(None, Output::Alive));
+                }
+            }
+        }
+    }
+
+    async fn tick_retention(&self) {
+        let retention = if let Some(retention) = self.retention {
+            retention
+        } else {
+            // Never finish, so that `run` keeps processing inputs
+            return future::pending().await;
+        };
+        // Prune right away, history replayed on startup may already be past the limits
+        loop {
+            let pruned = self.storage.write().await.prune(&retention, Utc::now());
+            if pruned > 0 {
+                info!("Pruned {} messages past the retention limits", pruned);
+                self.metrics.messages_pruned.inc_by(pruned as u64);
+            }
+            time::sleep(retention.interval).await;
+        }
+    }
+
+    async fn tick_sessions(&self) {
+        let resume_window = if let Some(resume_window) = self.resume_window {
+            resume_window
+        } else {
+            // Never finish, so that `run` keeps processing inputs
+            return future::pending().await;
+        };
+        loop {
+            time::sleep(resume_window).await;
+            // Users that did not resume in time are gone for good
+            let expired_user_ids: Vec<Uuid> = self
+                .sessions
+                .read()
+                .await
+                .values()
+                .filter(|session| {
+                    session
+                        .disconnected_at
+                        .is_some_and(|at| at.elapsed() >= resume_window)
+                })
+                .map(|session| session.user_id)
+                .collect();
+            for user_id in expired_user_ids {
+                self.remove_user(user_id).await;
+            }
+        }
+    }
+
+    async fn tick_typing(&self) {
+        let typing_timeout = if let Some(typing_timeout) = self.typing_timeout {
+            typing_timeout
+        } else {
+            // Never finish, so that `run` keeps processing inputs
+            return future::pending().await;
+        };
+        loop {
+            time::sleep(typing_timeout).await;
+            // Clients that went quiet are no longer typing
+            let expired_user_ids: Vec<Uuid> = self
+                .typing
+                .read()
+                .await
+                .iter()
+                .filter(|(_, updated_at)| updated_at.elapsed() >= typing_timeout)
+                .map(|(user_id, _)| *user_id)
+                .collect();
+            for user_id in expired_user_ids {
+                self.stop_typing(user_id).await;
+            }
+        }
+    }
+
+    async fn send(&self, output: Output) {
+        let sessions = self.sessions.read().await;
+        self.broadcast(sessions.values(), output);
+    }
+
+    fn send_targeted(&self, client_id: Uuid, output: Output) {
+        if let Some(outbox) = self.outboxes.read().unwrap().get(&client_id) {
+            self.deliver(outbox, OutputParcel::new(None, output));
+        }
+    }
+
+    async fn send_user(&self, user_id: Uuid, output: Output) {
+        let sessions = self.sessions.read().await;
+        self.broadcast(sessions.get(&user_id).into_iter(), output);
+    }
+
+    async fn send_ignored(&self, ignored_user_id: Uuid, output: Output) {
+        let sessions = self.sessions.read().await;
+        self.broadcast(
+            sessions
+                .values()
+                .filter(|session| session.user_id != ignored_user_id),
+            output,
+        );
+    }
+
+    async fn send_room(&self, room_id: Uuid, ignored_user_id: Uuid, output: Output) {
+        let rooms = self.rooms.read().await;
+        let room = if let Some(room) = rooms.get(&room_id) {
+            room
+        } else {
+            return;
+        };
+        let sessions = self.sessions.read().await;
+        self.broadcast(
+            room.users
+                .iter()
+                .filter(|user_id| **user_id != ignored_user_id)
+                .filter_map(|user_id| sessions.get(user_id)),
+            output,
+        );
+    }
+
+    // Numbers the output in each user's journal and delivers it to whoever is
+    // connected. Users waiting to resume still get it recorded, so that they
+    // can catch up on it.
+    fn broadcast<'a>(&self, sessions: impl Iterator<Item = &'a Session>, output: Output) {
+        let outboxes = self.outboxes.read().unwrap();
+        let versions = self.versions.read().unwrap();
+        let capabilities = self.capabilities.read().unwrap();
+        let mut journals = self.journals.lock().unwrap();
+        for session in sessions {
+            let outbox = match session.client_id {
+                Some(client_id) if understands(&capabilities, &client_id, &output) => {
+                    outboxes.get(&client_id)
+                }
+                // Users that are away get what their last connection asked for
+                None if allows(&session.capabilities, &output) => None,
+                _ => continue,
+            };
+            let parcel = journals
+                .entry(session.user_id)
+                .or_insert_with(|| Journal::new(self.journal_capacity))
+                .record(output.clone());
+            if let (Some(outbox), Some(client_id)) = (outbox, session.client_id) {
+                // Older clients would not know what to make of the number
+                let numbered = versions
+                    .get(&client_id)
+                    .is_some_and(|version| *version >= SEQ_PROTOCOL_VERSION);
+                if numbered {
+                    self.deliver(outbox, parcel);
+                } else {
+                    self.deliver(outbox, OutputParcel::new(None, parcel.output));
+                }
+            }
+        }
+    }
+
+    fn version(&self, client_id: Uuid) -> u32 {
+        self.versions
+            .read()
+            .unwrap()
+            .get(&client_id)
+            .copied()
+            .unwrap_or(MIN_PROTOCOL_VERSION)
+    }
+
+    fn deliver(&self, outbox: &Outbox, parcel: OutputParcel) {
+        if !outbox.push_parcel(parcel) {
+            self.metrics.lag_events.inc();
+        }
+    }
+
+    fn remove_outbox(&self, client_id: Uuid) -> Option<Arc<Outbox>> {
+        let mut outboxes = self.outboxes.write().unwrap();
+        let outbox = outboxes.remove(&client_id);
+        self.metrics.connected_clients.set(outboxes.len() as i64);
+        outbox
+    }
+
+    fn send_error(&self, client_id: Uuid, error: OutputError) {
+        self.metrics
+            .rejected_inputs
+            .with_label_values(&[error.code()])
+            .inc();
+        self.send_targeted(client_id, Output::Error(error));
+    }
+
+    fn send_internal_error(&self, client_id: Uuid, err: Error) {
+        error!("Failed to process input from {}: {}", client_id, err);
+        self.send_error(client_id, OutputError::Internal);
+    }
+}
+
+// Post a user made with a nonce, kept to answer retries of it
+#[derive(Clone, Copy)]
+struct RecentPost {
+    room_id: Uuid,
+    message_id: Uuid,
+    posted_at: Instant,
+}
+
+fn message_output(message: &Message) -> MessageOutput {
+    MessageOutput::new(
+        message.id,
+        UserOutput::new(message.user.id, &message.user.name),
+        &message.body,
+        message.created_at,
+        message.edited_at,
+        message.deleted,
+        reaction_outputs(message),
+    )
+    .with_thread(message.reply_to, message.thread_root, message.reply_count)
+}
+
+fn reaction_outputs(message: &Message) -> Vec<ReactionOutput> {
+    message
+        .reactions_by_emoji()
+        .into_iter()
+        .map(|(emoji, user_ids)| ReactionOutput::new(emoji, user_ids))
+        .collect()
+}
+
+// A short run of symbols, rather than words that would turn reactions into chat
+fn is_emoji(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_EMOJI_LENGTH
+        && !value.is_ascii()
+        && !value
+            .chars()
+            .any(|c| c.is_alphabetic() || c.is_whitespace() || c.is_control())
+}
+
+// Mutes and bans lapse on their own, so expired entries are dropped on lookup
+fn is_active<K: Eq + Hash>(until: &mut HashMap<K, Instant>, key: &K) -> bool {
+    match until.get(key) {
+        Some(until) if *until > Instant::now() => true,
+        Some(_) => {
+            until.remove(key);
+            false
+        }
+        None => false,
+    }
+}
+
+// Replies always get through, but other outputs only reach clients that said
+// they understand them
+fn understands(
+    capabilities: &HashMap<Uuid, Vec<Capability>>,
+    client_id: &Uuid,
+    output: &Output,
+) -> bool {
+    capabilities
+        .get(client_id)
+        .map_or(output.capability().is_none(), |capabilities| {
+            allows(capabilities, output)
+        })
+}
+
+fn allows(capabilities: &[Capability], output: &Output) -> bool {
+    match output.capability() {
+        Some(capability) => capabilities.contains(&capability),
+        None => true,
+    }
+}
+
+// Both participants map to the same feed regardless of who sent the message
+fn conversation_id(a: Uuid, b: Uuid) -> Uuid {
+    if a < b {
+        Uuid::new_v5(&a, b.as_bytes())
+    } else {
+        Uuid::new_v5(&b, a.as_bytes())
+    }
+}
+
+impl Health {
+    /// The hub loop is running and keeping up with its inputs.
+    pub fn is_live(&self) -> bool {
+        self.running && !self.stalled
+    }
+
+    /// Live, and messages can be stored.
+    pub fn is_ready(&self) -> bool {
+        self.is_live() && self.storage_available
+    }
+}
+
+impl Default for HubOptions {
+    fn default() -> Self {
+        HubOptions {
+            alive_interval: None,
+            resume_window: None,
+            typing_timeout: None,
+            lag_policy: LagPolicy::default(),
+            rate_limit: None,
+            dedupe_window: None,
+            journal_capacity: JOURNAL_CAPACITY,
+            retention: None,
+            moderators: Vec::new(),
+            reserved_names: Vec::new(),
+            authenticator: None,
+            max_message_body_length: MAX_MESSAGE_BODY_LENGTH,
+            outbox_capacity: OUTBOX_CAPACITY,
+        }
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new(HubOptions::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time;
+
+    use chrono::{Duration, Utc};
+    use tokio::runtime::Runtime;
+
+    use tokio::sync::mpsc::{self, UnboundedSender};
+    use uuid::Uuid;
+
+    use crate::auth::TokenAuthenticator;
+    use crate::client::CloseFrame;
+    use crate::hub::{Hub, HubOptions};
+    use crate::limiter::RateLimit;
+    use crate::model::message::Message;
+    use crate::model::user::User;
+    use crate::outbox::{CloseReason, Outbox};
+    use crate::proto::{
+        BanInput, BanTarget, Capability, CatchUpInput, CaughtUpOutput, CreateRoomInput,
+        DeleteMessageInput, DirectMessageInput, EditMessageInput, FetchHistoryInput,
+        FetchThreadInput, HelloInput, HelloOutput, Input, InputParcel, JoinInput, JoinRoomInput,
+        KickInput, LeaveRoomInput, ModerationAction, MuteInput, Output, OutputError, PostInput,
+        ReactInput, ReactionOutput, ReactionsChangedOutput, RepliesChangedOutput, ResumeInput,
+        TypingInput, UserTypingOutput, PROTOCOL_VERSION, SEQ_PROTOCOL_VERSION,
+    };
+    use crate::storage::{MemoryStorage, Retention, Storage};
+
+    // Connects a client that speaks the newest protocol and understands everything
+    async fn connect(
+        hub: &Hub,
+        sender: &UnboundedSender<InputParcel>,
+        client_id: Uuid,
+        address: Option<IpAddr>,
+    ) -> Arc<Outbox> {
+        let outbox = hub.connect(client_id, address);
+        let capabilities = Capability::ALL
+            .iter()
+            .map(|capability| String::from(capability.name()))
+            .collect();
+        sender
+            .send(InputParcel::new(
+                client_id,
+                Input::Hello(HelloInput {
+                    version: PROTOCOL_VERSION,
+                    capabilities,
+                }),
+            ))
+            .unwrap();
+        assert!(matches!(outbox.recv().await, Some(Output::Hello(_))));
+        outbox
+    }
+
+    #[test]
+    fn join_and_post() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let client_id = Uuid::new_v4();
+                let outbox = connect(&hub, &sender, client_id, None).await;
+
+                // Names are letters and spaces only, all the way through
+                for name in &["John.1700000000", "Jo"] {
+                    sender
+                        .send(InputParcel::new(
+                            client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                    assert_eq!(
+                        outbox.recv().await,
+                        Some(Output::Error(OutputError::InvalidName))
+                    );
+                }
+
+                // Join
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                let output = outbox.recv().await.unwrap();
                 let user;
+                let room_id;
                 if let Output::Joined(joined) = output {
                     assert_eq!(joined.user.name.as_str(), "John");
+                    assert_eq!(joined.room.id, hub.default_room_id());
                     user = joined.user;
+                    room_id = joined.room.id;
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                }
+
+                // Post message
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                let output = outbox.recv().await.unwrap();
+                if let Output::Posted(posted) = output {
+                    assert_eq!(posted.room_id, room_id);
+                    assert_eq!(posted.message.body, "Hello");
+                    assert_eq!(posted.message.user.id, user.id);
+                    assert_eq!(posted.message.user.name, user.name);
+                } else {
+                    panic!("Expected Output::Posted got {:?}", output);
+                }
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn rooms() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let general_id = hub.default_room_id();
+
+                // Both users join and end up in the default room
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                assert!(matches!(john.recv().await, Some(Output::Joined(_))));
+                let output = jane.recv().await.unwrap();
+                if let Output::Joined(joined) = output {
+                    assert_eq!(joined.room.id, general_id);
+                    assert_eq!(joined.others.len(), 1);
+                    assert_eq!(joined.others[0].id, john_id);
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                }
+                let output = john.recv().await.unwrap();
+                if let Output::UserJoined(user_joined) = output {
+                    assert_eq!(user_joined.room_id, general_id);
+                    assert_eq!(user_joined.user.id, jane_id);
+                } else {
+                    panic!("Expected Output::UserJoined got {:?}", output);
+                }
+
+                // John creates a room and enters it
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::CreateRoom(CreateRoomInput {
+                            name: String::from("Random"),
+                        }),
+                    ))
+                    .unwrap();
+                let output = jane.recv().await.unwrap();
+                let room_id = if let Output::RoomCreated(room_created) = output {
+                    assert_eq!(room_created.room.name, "Random");
+                    room_created.room.id
+                } else {
+                    panic!("Expected Output::RoomCreated got {:?}", output);
+                };
+                let output = john.recv().await.unwrap();
+                if let Output::Joined(joined) = output {
+                    assert_eq!(joined.room.id, room_id);
+                    assert!(joined.others.is_empty());
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                }
+
+                // Posts only reach members of the room
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(john.recv().await, Some(Output::Posted(_))));
+
+                sender
+                    .send(InputParcel::new(
+                        jane_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::Error(OutputError::NotInRoom))
+                );
+
+                // Jane joins and receives only the history of that room
+                sender
+                    .send(InputParcel::new(
+                        jane_id,
+                        Input::JoinRoom(JoinRoomInput { room_id }),
+                    ))
+                    .unwrap();
+                let output = jane.recv().await.unwrap();
+                if let Output::Joined(joined) = output {
+                    assert_eq!(joined.room.id, room_id);
+                    assert_eq!(joined.messages.len(), 1);
+                    assert_eq!(joined.messages[0].user.id, john_id);
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                }
+                assert!(matches!(john.recv().await, Some(Output::UserJoined(_))));
+
+                // John leaves
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::LeaveRoom(LeaveRoomInput { room_id }),
+                    ))
+                    .unwrap();
+                assert!(matches!(john.recv().await, Some(Output::Left(_))));
+                let output = jane.recv().await.unwrap();
+                if let Output::UserLeft(user_left) = output {
+                    assert_eq!(user_left.room_id, room_id);
+                    assert_eq!(user_left.user_id, john_id);
+                } else {
+                    panic!("Expected Output::UserLeft got {:?}", output);
+                }
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn direct_message() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let bob_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let bob = connect(&hub, &sender, bob_id, None).await;
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane"), (bob_id, "Bobby")]
+                {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                // Skip join notifications
+                for _ in 0..3 {
+                    john.recv().await.unwrap();
+                }
+                for _ in 0..2 {
+                    jane.recv().await.unwrap();
+                }
+                bob.recv().await.unwrap();
+
+                // Only sender and recipient receive the message
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::DirectMessage(DirectMessageInput {
+                            to: jane_id,
+                            body: String::from("Psst"),
+                        }),
+                    ))
+                    .unwrap();
+                for outbox in &[&john, &jane] {
+                    let output = outbox.recv().await.unwrap();
+                    if let Output::DirectMessage(direct_message) = output {
+                        assert_eq!(direct_message.to, jane_id);
+                        assert_eq!(direct_message.message.user.id, john_id);
+                        assert_eq!(direct_message.message.body, "Psst");
+                    } else {
+                        panic!("Expected Output::DirectMessage got {:?}", output);
+                    }
+                }
+                assert!(bob.is_empty());
+
+                // Unknown recipient
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::DirectMessage(DirectMessageInput {
+                            to: Uuid::new_v4(),
+                            body: String::from("Psst"),
+                        }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    john.recv().await,
+                    Some(Output::Error(OutputError::UnknownRecipient))
+                );
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn fetch_history() {
+        let mut storage = MemoryStorage::new();
+        let author = User::new(Uuid::new_v4(), "Jane");
+        let start = Utc::now() - Duration::hours(1);
+        for i in 0..60 {
+            storage
+                .add_message(
+                    Uuid::nil(),
+                    Message::new(
+                        Uuid::new_v4(),
+                        author.clone(),
+                        &i.to_string(),
+                        start + Duration::seconds(i),
+                    ),
+                )
+                .unwrap();
+        }
+        let hub = Hub::with_storage(HubOptions::default(), Box::new(storage));
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let client_id = Uuid::new_v4();
+                let outbox = connect(&hub, &sender, client_id, None).await;
+                let room_id = hub.default_room_id();
+
+                // Join only receives the latest messages
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                let output = outbox.recv().await.unwrap();
+                let cursor = if let Output::Joined(joined) = output {
+                    assert_eq!(joined.messages.len(), 50);
+                    assert_eq!(joined.messages[0].body, "10");
+                    assert_eq!(joined.messages[49].body, "59");
+                    assert_eq!(joined.cursor, Some(joined.messages[0].id));
+                    joined.cursor
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                };
+
+                // Scroll back to the beginning
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::FetchHistory(FetchHistoryInput {
+                            room_id,
+                            before: cursor,
+                            limit: 50,
+                        }),
+                    ))
+                    .unwrap();
+                let output = outbox.recv().await.unwrap();
+                if let Output::History(history) = output {
+                    assert_eq!(history.room_id, room_id);
+                    assert_eq!(history.messages.len(), 10);
+                    assert_eq!(history.messages[0].body, "0");
+                    assert_eq!(history.messages[9].body, "9");
+                    assert_eq!(history.cursor, None);
+                } else {
+                    panic!("Expected Output::History got {:?}", output);
+                }
+
+                // An empty page would leave nowhere to go on from
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::FetchHistory(FetchHistoryInput {
+                            room_id,
+                            before: cursor,
+                            limit: 0,
+                        }),
+                    ))
+                    .unwrap();
+                let output = outbox.recv().await.unwrap();
+                if let Output::History(history) = output {
+                    assert_eq!(history.messages.len(), 1);
+                    assert_eq!(history.messages[0].body, "9");
+                    assert_eq!(history.cursor, Some(history.messages[0].id));
+                } else {
+                    panic!("Expected Output::History got {:?}", output);
+                }
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn edit_and_delete() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                // Skip join notifications
+                for _ in 0..2 {
+                    john.recv().await.unwrap();
+                }
+                jane.recv().await.unwrap();
+                let output = john.recv().await.unwrap();
+                let message_id = if let Output::Posted(posted) = output {
+                    posted.message.id
+                } else {
+                    panic!("Expected Output::Posted got {:?}", output);
+                };
+                assert!(matches!(jane.recv().await, Some(Output::UserPosted(_))));
+
+                // Only the author may change a message
+                sender
+                    .send(InputParcel::new(
+                        jane_id,
+                        Input::DeleteMessage(DeleteMessageInput {
+                            room_id,
+                            message_id,
+                        }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::Error(OutputError::NotMessageAuthor))
+                );
+
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::EditMessage(EditMessageInput {
+                            room_id,
+                            message_id,
+                            body: String::from("Hi"),
+                        }),
+                    ))
+                    .unwrap();
+                for outbox in &[&john, &jane] {
+                    let output = outbox.recv().await.unwrap();
+                    if let Output::MessageEdited(edited) = output {
+                        assert_eq!(edited.message.id, message_id);
+                        assert_eq!(edited.message.body, "Hi");
+                        assert!(edited.message.edited_at.is_some());
+                    } else {
+                        panic!("Expected Output::MessageEdited got {:?}", output);
+                    }
+                }
+
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::DeleteMessage(DeleteMessageInput {
+                            room_id,
+                            message_id,
+                        }),
+                    ))
+                    .unwrap();
+                for outbox in &[&john, &jane] {
+                    assert!(matches!(
+                        outbox.recv().await,
+                        Some(Output::MessageDeleted(_))
+                    ));
+                }
+
+                // Late joiners see a tombstone
+                let bob_id = Uuid::new_v4();
+                let bob = connect(&hub, &sender, bob_id, None).await;
+                sender
+                    .send(InputParcel::new(
+                        bob_id,
+                        Input::Join(JoinInput {
+                            name: String::from("Bobby"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                let output = bob.recv().await.unwrap();
+                if let Output::Joined(joined) = output {
+                    assert_eq!(joined.messages.len(), 1);
+                    assert_eq!(joined.messages[0].id, message_id);
+                    assert!(joined.messages[0].deleted);
+                    assert!(joined.messages[0].body.is_empty());
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                }
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn resume_session() {
+        let hub = Hub::new(HubOptions {
+            resume_window: Some(time::Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                let output = john.recv().await.unwrap();
+                let token = if let Output::Joined(joined) = output {
+                    joined.session_token.unwrap()
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                };
+                jane.recv().await.unwrap();
+
+                // Reconnect within the window keeps identity and rooms
+                hub.on_disconnect(john_id, None).await;
+                let reconnected_id = Uuid::new_v4();
+                let reconnected = connect(&hub, &sender, reconnected_id, None).await;
+                sender
+                    .send(InputParcel::new(
+                        reconnected_id,
+                        Input::Resume(ResumeInput {
+                            token: token.clone(),
+                        }),
+                    ))
+                    .unwrap();
+                let output = reconnected.recv().await.unwrap();
+                if let Output::Resumed(resumed) = output {
+                    assert_eq!(resumed.user.id, john_id);
+                    assert_eq!(resumed.rooms.len(), 1);
+                    assert_eq!(resumed.rooms[0].id, room_id);
+                } else {
+                    panic!("Expected Output::Resumed got {:?}", output);
+                }
+
+                sender
+                    .send(InputParcel::new(
+                        jane_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Welcome back"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(
+                    reconnected.recv().await,
+                    Some(Output::UserPosted(_))
+                ));
+                assert!(matches!(jane.recv().await, Some(Output::Posted(_))));
+
+                // A connection speaks for one user only
+                let inputs = vec![
+                    Input::Resume(ResumeInput {
+                        token: token.clone(),
+                    }),
+                    Input::Join(JoinInput {
+                        name: String::from("Jack"),
+                        credential: None,
+                    }),
+                ];
+                for input in inputs {
+                    sender.send(InputParcel::new(jane_id, input)).unwrap();
+                    assert_eq!(
+                        jane.recv().await,
+                        Some(Output::Error(OutputError::AlreadyJoined))
+                    );
+                }
+
+                // Others are only told once the window expires
+                hub.on_disconnect(reconnected_id, None).await;
+                let output = jane.recv().await.unwrap();
+                if let Output::UserLeft(user_left) = output {
+                    assert_eq!(user_left.user_id, john_id);
+                } else {
+                    panic!("Expected Output::UserLeft got {:?}", output);
+                }
+
+                let late_id = Uuid::new_v4();
+                let late = connect(&hub, &sender, late_id, None).await;
+                sender
+                    .send(InputParcel::new(
+                        late_id,
+                        Input::Resume(ResumeInput { token }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    late.recv().await,
+                    Some(Output::Error(OutputError::InvalidSession))
+                );
+
+                // Closing the connection normally ends the session right away
+                hub.on_disconnect(jane_id, Some(CloseFrame::new(1000, "")))
+                    .await;
+                assert!(hub.users().await.is_empty());
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn typing() {
+        let hub = Hub::new(HubOptions {
+            typing_timeout: Some(time::Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                // Skip join notifications
+                for _ in 0..2 {
+                    john.recv().await.unwrap();
+                }
+                jane.recv().await.unwrap();
+
+                // Repeated updates are announced only once
+                for _ in 0..3 {
+                    sender
+                        .send(InputParcel::new(
+                            john_id,
+                            Input::Typing(TypingInput { active: true }),
+                        ))
+                        .unwrap();
+                }
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::UserTyping(UserTypingOutput::new(john_id, true)))
+                );
+
+                // Posting stops typing
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(john.recv().await, Some(Output::Posted(_))));
+                assert!(matches!(jane.recv().await, Some(Output::UserPosted(_))));
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::UserTyping(UserTypingOutput::new(john_id, false)))
+                );
+
+                // Typing expires once the client goes quiet
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Typing(TypingInput { active: true }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::UserTyping(UserTypingOutput::new(john_id, true)))
+                );
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::UserTyping(UserTypingOutput::new(john_id, false)))
+                );
+                assert!(john.is_empty());
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn moderation() {
+        let hub = Hub::new(HubOptions {
+            moderators: vec![String::from("Alice")],
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let address: IpAddr = "10.0.0.1".parse().unwrap();
+                let alice_id = Uuid::new_v4();
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let alice = connect(&hub, &sender, alice_id, None).await;
+                let john = connect(&hub, &sender, john_id, Some(address)).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in
+                    &[(alice_id, "Alice"), (john_id, "John"), (jane_id, "Jane")]
+                {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                // Skip join notifications
+                for _ in 0..3 {
+                    alice.recv().await.unwrap();
+                }
+                for _ in 0..2 {
+                    john.recv().await.unwrap();
+                }
+                jane.recv().await.unwrap();
+
+                // Only moderators may moderate
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Kick(KickInput { user_id: jane_id }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    john.recv().await,
+                    Some(Output::Error(OutputError::NotModerator))
+                );
+
+                // Durations that would overflow the clock are refused
+                sender
+                    .send(InputParcel::new(
+                        alice_id,
+                        Input::Mute(MuteInput {
+                            user_id: john_id,
+                            duration_secs: u64::MAX,
+                        }),
+                    ))
+                    .unwrap();
+                sender
+                    .send(InputParcel::new(
+                        alice_id,
+                        Input::Ban(BanInput {
+                            target: BanTarget::Name(String::from("John")),
+                            duration_secs: u64::MAX,
+                        }),
+                    ))
+                    .unwrap();
+                for _ in 0..2 {
+                    assert_eq!(
+                        alice.recv().await,
+                        Some(Output::Error(OutputError::MalformedInput))
+                    );
+                }
+
+                // Muted users cannot post
+                sender
+                    .send(InputParcel::new(
+                        alice_id,
+                        Input::Mute(MuteInput {
+                            user_id: john_id,
+                            duration_secs: 60,
+                        }),
+                    ))
+                    .unwrap();
+                for outbox in &[&alice, &john, &jane] {
+                    let output = outbox.recv().await.unwrap();
+                    if let Output::Moderated(moderated) = output {
+                        assert_eq!(moderated.action, ModerationAction::Mute);
+                        assert_eq!(moderated.moderator.id, alice_id);
+                        assert_eq!(moderated.users.len(), 1);
+                        assert_eq!(moderated.users[0].id, john_id);
+                        assert!(moderated.until.is_some());
+                    } else {
+                        panic!("Expected Output::Moderated got {:?}", output);
+                    }
+                }
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert_eq!(john.recv().await, Some(Output::Error(OutputError::Muted)));
+
+                // Kicked users are disconnected and leave their rooms
+                sender
+                    .send(InputParcel::new(
+                        alice_id,
+                        Input::Kick(KickInput { user_id: john_id }),
+                    ))
+                    .unwrap();
+                assert!(matches!(john.recv().await, Some(Output::Moderated(_))));
+                assert_eq!(john.recv().await, None);
+                assert_eq!(john.close_reason(), Some(CloseReason::Kicked));
+                assert!(matches!(jane.recv().await, Some(Output::Moderated(_))));
+                let output = jane.recv().await.unwrap();
+                if let Output::UserLeft(user_left) = output {
+                    assert_eq!(user_left.user_id, john_id);
+                } else {
+                    panic!("Expected Output::UserLeft got {:?}", output);
+                }
+                hub.on_disconnect(john_id, None).await;
+
+                // Banned addresses cannot connect
+                sender
+                    .send(InputParcel::new(
+                        alice_id,
+                        Input::Ban(BanInput {
+                            target: BanTarget::Address(address),
+                            duration_secs: 60,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(jane.recv().await, Some(Output::Moderated(_))));
+                let banned_id = Uuid::new_v4();
+                let banned = hub.connect(banned_id, Some(address));
+                assert_eq!(
+                    banned.recv().await,
+                    Some(Output::Error(OutputError::Banned))
+                );
+                assert_eq!(banned.recv().await, None);
+                assert_eq!(banned.close_reason(), Some(CloseReason::Banned));
+                // Nothing the banned client sends gets through
+                sender
+                    .send(InputParcel::new(
+                        banned_id,
+                        Input::Join(JoinInput {
+                            name: String::from("Jim"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+
+                // Banned names cannot join again
+                sender
+                    .send(InputParcel::new(
+                        alice_id,
+                        Input::Ban(BanInput {
+                            target: BanTarget::Name(String::from("Jane")),
+                            duration_secs: 60,
+                        }),
+                    ))
+                    .unwrap();
+                let output = jane.recv().await.unwrap();
+                if let Output::Moderated(moderated) = output {
+                    assert_eq!(moderated.action, ModerationAction::Ban);
+                    assert_eq!(moderated.users[0].id, jane_id);
+                } else {
+                    panic!("Expected Output::Moderated got {:?}", output);
+                }
+                assert_eq!(jane.recv().await, None);
+                assert_eq!(jane.close_reason(), Some(CloseReason::Banned));
+                hub.on_disconnect(jane_id, None).await;
+
+                let rejoined_id = Uuid::new_v4();
+                let rejoined = connect(&hub, &sender, rejoined_id, None).await;
+                sender
+                    .send(InputParcel::new(
+                        rejoined_id,
+                        Input::Join(JoinInput {
+                            name: String::from("Jane"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    rejoined.recv().await,
+                    Some(Output::Error(OutputError::Banned))
+                );
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn rate_limit() {
+        let hub = Hub::new(HubOptions {
+            rate_limit: Some(RateLimit {
+                burst: 3,
+                interval: time::Duration::from_secs(60),
+            }),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let client_id = Uuid::new_v4();
+                let outbox = connect(&hub, &sender, client_id, None).await;
+                let room_id = hub.default_room_id();
+
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(outbox.recv().await, Some(Output::Joined(_))));
+
+                // Joining takes a token too, posts beyond the burst are
+                // rejected until the bucket refills
+                for _ in 0..3 {
+                    sender
+                        .send(InputParcel::new(
+                            client_id,
+                            Input::Post(PostInput {
+                                room_id,
+                                body: String::from("Hello"),
+                                nonce: None,
+                                reply_to: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                for _ in 0..2 {
+                    assert!(matches!(outbox.recv().await, Some(Output::Posted(_))));
+                }
+                let output = outbox.recv().await.unwrap();
+                if let Output::Error(OutputError::RateLimited { retry_after_ms }) = output {
+                    assert!(retry_after_ms > 0 && retry_after_ms <= 60_000);
+                } else {
+                    panic!("Expected OutputError::RateLimited got {:?}", output);
+                }
+
+                // Everything else that changes anything is limited the same way
+                let inputs = vec![
+                    Input::CreateRoom(CreateRoomInput {
+                        name: String::from("Rust"),
+                    }),
+                    Input::DirectMessage(DirectMessageInput {
+                        to: client_id,
+                        body: String::from("Hi"),
+                    }),
+                    Input::Typing(TypingInput { active: true }),
+                ];
+                for input in inputs {
+                    sender.send(InputParcel::new(client_id, input)).unwrap();
+                    assert!(matches!(
+                        outbox.recv().await,
+                        Some(Output::Error(OutputError::RateLimited { .. }))
+                    ));
+                }
+                // But reads are not
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::FetchHistory(FetchHistoryInput {
+                            room_id,
+                            before: None,
+                            limit: 10,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(outbox.recv().await, Some(Output::History(_))));
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn metrics() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let client_id = Uuid::new_v4();
+                let outbox = connect(&hub, &sender, client_id, None).await;
+                let room_id = hub.default_room_id();
+
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                for room_id in &[room_id, Uuid::new_v4()] {
+                    sender
+                        .send(InputParcel::new(
+                            client_id,
+                            Input::Post(PostInput {
+                                room_id: *room_id,
+                                body: String::from("Hello"),
+                                nonce: None,
+                                reply_to: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                for _ in 0..3 {
+                    outbox.recv().await.unwrap();
+                }
+
+                let metrics = hub.metrics();
+                assert_eq!(metrics.connected_clients.get(), 1);
+                assert_eq!(metrics.joined_users.get(), 1);
+                assert_eq!(metrics.messages_posted.get(), 1);
+                assert_eq!(
+                    metrics
+                        .rejected_inputs
+                        .with_label_values(&["room-not-found"])
+                        .get(),
+                    1
+                );
+                assert_eq!(
+                    metrics
+                        .input_latency
+                        .with_label_values(&["post"])
+                        .get_sample_count(),
+                    2
+                );
+                assert!(metrics
+                    .encode()
+                    .contains("chat_rejected_inputs_total{code=\"room-not-found\"} 1"));
+
+                hub.on_disconnect(client_id, None).await;
+                assert_eq!(metrics.connected_clients.get(), 0);
+                assert_eq!(metrics.joined_users.get(), 0);
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn health() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let health = hub.health().await;
+            assert!(!health.running);
+            assert!(!health.is_live());
+
+            let case = async {
+                let client_id = Uuid::new_v4();
+                let outbox = connect(&hub, &sender, client_id, None).await;
+
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                outbox.recv().await.unwrap();
+
+                let health = hub.health().await;
+                assert!(health.running);
+                assert!(!health.stalled);
+                assert!(health.last_processed_at.is_some());
+                assert!(health.storage_available);
+                assert_eq!(health.connected_clients, 1);
+                assert!(health.is_ready());
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn authenticate() {
+        let tokens = TokenAuthenticator::new(b"secret");
+        let token = tokens.issue("John", Utc::now() + Duration::hours(1));
+        let hub = Hub::new(HubOptions {
+            authenticator: Some(Arc::new(tokens)),
+            rate_limit: Some(RateLimit {
+                burst: 5,
+                interval: time::Duration::from_secs(60),
+            }),
+            ..Default::default()
+        });
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let client_id = Uuid::new_v4();
+            let outbox = hub.connect(client_id, None);
+
+            // Jane cannot join without a token of her own
+            for credential in &[None, Some(String::from("forged")), Some(token.clone())] {
+                let input = JoinInput {
+                    name: String::from("Jane"),
+                    credential: credential.clone(),
+                };
+                assert!(!hub.authenticate(client_id, &input).await);
+                assert_eq!(
+                    outbox.recv().await,
+                    Some(Output::Error(OutputError::Unauthorized))
+                );
+            }
+
+            let input = JoinInput {
+                name: String::from(" John "),
+                credential: Some(token),
+            };
+            assert!(hub.authenticate(client_id, &input).await);
+            assert!(outbox.is_empty());
+
+            // Guesses use up the rate limit like any other input
+            let input = JoinInput {
+                name: String::from("Jane"),
+                credential: Some(String::from("guess")),
+            };
+            assert!(!hub.authenticate(client_id, &input).await);
+            assert_eq!(
+                outbox.recv().await,
+                Some(Output::Error(OutputError::Unauthorized))
+            );
+            assert!(!hub.authenticate(client_id, &input).await);
+            assert!(matches!(
+                outbox.recv().await,
+                Some(Output::Error(OutputError::RateLimited { .. }))
+            ));
+        });
+    }
+
+    #[test]
+    fn hello() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                // John never says hello, like clients written before it existed
+                let john = hub.connect(john_id, None);
+                let jane = hub.connect(jane_id, None);
+                let room_id = hub.default_room_id();
+
+                for version in &[0, 7] {
+                    sender
+                        .send(InputParcel::new(
+                            jane_id,
+                            Input::Hello(HelloInput {
+                                version: *version,
+                                capabilities: vec![
+                                    String::from("typing"),
+                                    String::from("telepathy"),
+                                ],
+                            }),
+                        ))
+                        .unwrap();
+                }
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::Error(OutputError::UnsupportedVersion {
+                        min_version: 1,
+                        max_version: PROTOCOL_VERSION,
+                    }))
+                );
+                // Newer clients are spoken to in the newest version the server knows
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::Hello(HelloOutput::new(
+                        PROTOCOL_VERSION,
+                        Capability::ALL.to_vec()
+                    )))
+                );
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                // Skip join notifications
+                for _ in 0..2 {
+                    john.recv().await.unwrap();
+                }
+                jane.recv().await.unwrap();
+
+                for client_id in &[jane_id, john_id] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Typing(TypingInput { active: true }),
+                        ))
+                        .unwrap();
+                }
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::UserTyping(UserTypingOutput::new(john_id, true)))
+                );
+
+                // Replies get through without the capability
+                sender
+                    .send(InputParcel::new(
+                        jane_id,
+                        Input::FetchHistory(FetchHistoryInput {
+                            room_id,
+                            before: None,
+                            limit: 10,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(jane.recv().await, Some(Output::History(_))));
+
+                sender
+                    .send(InputParcel::new(
+                        jane_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(jane.recv().await, Some(Output::Posted(_))));
+                // John missed out on Jane typing, and gets no sequence numbers
+                let parcel = john.recv_parcel().await.unwrap();
+                assert!(matches!(parcel.output, Output::UserPosted(_)));
+                assert_eq!(parcel.seq, None);
+                assert!(john.is_empty());
+
+                // The first version had no rooms, so posts go to the default one
+                sender
+                    .send(InputParcel::new(
+                        john_id,
+                        Input::Post(PostInput {
+                            room_id: Uuid::new_v4(),
+                            body: String::from("Hi"),
+                            nonce: None,
+                            reply_to: None,
+                        }),
+                    ))
+                    .unwrap();
+                match john.recv().await {
+                    Some(Output::Posted(posted)) => assert_eq!(posted.room_id, room_id),
+                    output => panic!("Expected Output::Posted got {:?}", output),
+                }
+                let parcel = jane.recv_parcel().await.unwrap();
+                assert!(matches!(parcel.output, Output::UserPosted(_)));
+                assert!(parcel.seq.is_some());
+
+                // Capabilities newer than the version are neither offered nor used
+                let old_id = Uuid::new_v4();
+                let old = hub.connect(old_id, None);
+                sender
+                    .send(InputParcel::new(
+                        old_id,
+                        Input::Hello(HelloInput {
+                            version: 3,
+                            capabilities: vec![String::from("reactions")],
+                        }),
+                    ))
+                    .unwrap();
+                match old.recv().await {
+                    Some(Output::Hello(hello)) => {
+                        assert_eq!(hello.version, 3);
+                        assert!(!hello.capabilities.contains(&Capability::Reactions));
+                        assert!(hello.capabilities.contains(&Capability::Rooms));
+                    }
+                    output => panic!("Expected Output::Hello got {:?}", output),
+                }
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn dedupe_posts() {
+        let hub = Hub::new(HubOptions {
+            dedupe_window: Some(time::Duration::from_secs(60)),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                // Skip join notifications
+                for _ in 0..2 {
+                    john.recv().await.unwrap();
+                }
+                jane.recv().await.unwrap();
+
+                let post = |nonce: &str| {
+                    InputParcel::new(
+                        john_id,
+                        Input::Post(PostInput {
+                            room_id,
+                            body: String::from("Hello"),
+                            nonce: Some(String::from(nonce)),
+                            reply_to: None,
+                        }),
+                    )
+                };
+                // John retries his first post, then posts again
+                for nonce in &["first", "first", "second"] {
+                    sender.send(post(nonce)).unwrap();
+                }
+                let mut message_ids = Vec::new();
+                for nonce in &["first", "first", "second"] {
+                    match john.recv().await {
+                        Some(Output::Posted(posted)) => {
+                            assert_eq!(posted.nonce.as_deref(), Some(*nonce));
+                            message_ids.push(posted.message.id);
+                        }
+                        output => panic!("Expected Output::Posted got {:?}", output),
+                    }
+                }
+                assert_eq!(message_ids[0], message_ids[1]);
+                assert_ne!(message_ids[0], message_ids[2]);
+
+                // Jane only hears about each message once
+                for message_id in &[message_ids[0], message_ids[2]] {
+                    match jane.recv().await {
+                        Some(Output::UserPosted(user_posted)) => {
+                            assert_eq!(user_posted.message.id, *message_id)
+                        }
+                        output => panic!("Expected Output::UserPosted got {:?}", output),
+                    }
+                }
+                assert!(jane.is_empty());
+
+                sender.send(post(&"x".repeat(65))).unwrap();
+                assert_eq!(
+                    john.recv().await,
+                    Some(Output::Error(OutputError::MalformedInput))
+                );
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn catch_up() {
+        let hub = Hub::new(HubOptions {
+            resume_window: Some(time::Duration::from_secs(60)),
+            journal_capacity: 3,
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                let output = john.recv().await.unwrap();
+                let token = if let Output::Joined(joined) = output {
+                    joined.session_token.unwrap()
                 } else {
                     panic!("Expected Output::Joined got {:?}", output);
+                };
+                // Replies are not numbered, but everything else is
+                let parcel = john.recv_parcel().await.unwrap();
+                assert_eq!(parcel.seq, Some(1));
+                assert!(matches!(parcel.output, Output::UserJoined(_)));
+                jane.recv().await.unwrap();
+
+                // Jane posts while John is away
+                hub.on_disconnect(john_id, None).await;
+                let post = InputParcel::new(
+                    jane_id,
+                    Input::Post(PostInput {
+                        room_id,
+                        body: String::from("Hello"),
+                        nonce: None,
+                        reply_to: None,
+                    }),
+                );
+                for _ in 0..2 {
+                    sender.send(post.clone()).unwrap();
+                    assert!(matches!(jane.recv().await, Some(Output::Posted(_))));
                 }
 
-                // Post message
+                let reconnected_id = Uuid::new_v4();
+                let reconnected = connect(&hub, &sender, reconnected_id, None).await;
+                for input in [
+                    Input::Resume(ResumeInput { token }),
+                    Input::CatchUp(CatchUpInput { since_seq: 1 }),
+                ] {
+                    sender
+                        .send(InputParcel::new(reconnected_id, input))
+                        .unwrap();
+                }
+                assert!(matches!(reconnected.recv().await, Some(Output::Resumed(_))));
+                for seq in 2..4 {
+                    let parcel = reconnected.recv_parcel().await.unwrap();
+                    assert_eq!(parcel.seq, Some(seq));
+                    assert!(matches!(parcel.output, Output::UserPosted(_)));
+                }
+                assert_eq!(
+                    reconnected.recv().await,
+                    Some(Output::CaughtUp(CaughtUpOutput::new(3)))
+                );
+
+                // Only the latest three outputs are kept
+                sender.send(post).unwrap();
+                assert_eq!(reconnected.recv_parcel().await.unwrap().seq, Some(4));
+                sender
+                    .send(InputParcel::new(
+                        reconnected_id,
+                        Input::CatchUp(CatchUpInput { since_seq: 0 }),
+                    ))
+                    .unwrap();
+                assert_eq!(
+                    reconnected.recv().await,
+                    Some(Output::Error(OutputError::CatchUpUnavailable {
+                        last_seq: 4
+                    }))
+                );
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn catch_up_as_negotiated() {
+        let hub = Hub::new(HubOptions {
+            resume_window: Some(time::Duration::from_secs(60)),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            // John only ever asks for history
+            let (hub_ref, sender_ref) = (&hub, &sender);
+            let hello = |client_id, version| async move {
+                let outbox = hub_ref.connect(client_id, None);
+                sender_ref
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Hello(HelloInput {
+                            version,
+                            capabilities: vec![String::from("history")],
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(outbox.recv().await, Some(Output::Hello(_))));
+                outbox
+            };
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = hello(john_id, PROTOCOL_VERSION).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                let output = john.recv().await.unwrap();
+                let token = if let Output::Joined(joined) = output {
+                    joined.session_token.unwrap()
+                } else {
+                    panic!("Expected Output::Joined got {:?}", output);
+                };
+                assert_eq!(john.recv_parcel().await.unwrap().seq, Some(1));
+                jane.recv().await.unwrap();
+
+                let post = InputParcel::new(
+                    jane_id,
+                    Input::Post(PostInput {
+                        room_id,
+                        body: String::from("Hello"),
+                        nonce: None,
+                        reply_to: None,
+                    }),
+                );
+                let typing = InputParcel::new(jane_id, Input::Typing(TypingInput { active: true }));
+
+                // Typing John would not have been told about leaves no gap
+                hub.on_disconnect(john_id, None).await;
+                sender.send(typing).unwrap();
+                sender.send(post.clone()).unwrap();
+                assert!(matches!(jane.recv().await, Some(Output::Posted(_))));
+
+                let reconnected_id = Uuid::new_v4();
+                let reconnected = hello(reconnected_id, PROTOCOL_VERSION).await;
+                for input in [
+                    Input::Resume(ResumeInput {
+                        token: token.clone(),
+                    }),
+                    Input::CatchUp(CatchUpInput { since_seq: 1 }),
+                ] {
+                    sender
+                        .send(InputParcel::new(reconnected_id, input))
+                        .unwrap();
+                }
+                assert!(matches!(reconnected.recv().await, Some(Output::Resumed(_))));
+                let parcel = reconnected.recv_parcel().await.unwrap();
+                assert_eq!(parcel.seq, Some(2));
+                assert!(matches!(parcel.output, Output::UserPosted(_)));
+                assert_eq!(
+                    reconnected.recv().await,
+                    Some(Output::CaughtUp(CaughtUpOutput::new(2)))
+                );
+
+                // Clients too old for sequence numbers get none on replay either
+                hub.on_disconnect(reconnected_id, None).await;
+                sender.send(post).unwrap();
+                assert!(matches!(jane.recv().await, Some(Output::Posted(_))));
+
+                let old_id = Uuid::new_v4();
+                let old = hello(old_id, SEQ_PROTOCOL_VERSION - 1).await;
+                for input in [
+                    Input::Resume(ResumeInput { token }),
+                    Input::CatchUp(CatchUpInput { since_seq: 2 }),
+                ] {
+                    sender.send(InputParcel::new(old_id, input)).unwrap();
+                }
+                assert!(matches!(old.recv().await, Some(Output::Resumed(_))));
+                let parcel = old.recv_parcel().await.unwrap();
+                assert_eq!(parcel.seq, None);
+                assert!(matches!(parcel.output, Output::UserPosted(_)));
+                assert_eq!(
+                    old.recv().await,
+                    Some(Output::CaughtUp(CaughtUpOutput::new(3)))
+                );
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn retention() {
+        let hub = Hub::new(HubOptions {
+            retention: Some(Retention {
+                max_messages: Some(2),
+                max_age: None,
+                max_bytes: None,
+                interval: time::Duration::from_millis(50),
+            }),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let client_id = Uuid::new_v4();
+                let outbox = connect(&hub, &sender, client_id, None).await;
+                let room_id = hub.default_room_id();
                 sender
                     .send(InputParcel::new(
                         client_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                outbox.recv().await.unwrap();
+
+                let mut posted_at = Vec::new();
+                for body in &["One", "Two", "Three"] {
+                    sender
+                        .send(InputParcel::new(
+                            client_id,
+                            Input::Post(PostInput {
+                                room_id,
+                                body: String::from(*body),
+                                nonce: None,
+                                reply_to: None,
+                            }),
+                        ))
+                        .unwrap();
+                    match outbox.recv().await {
+                        Some(Output::Posted(posted)) => posted_at.push(posted.message.created_at),
+                        output => panic!("Expected Output::Posted got {:?}", output),
+                    }
+                }
+                assert_eq!(
+                    hub.history(room_id, None, 10).await.unwrap().pruned_until,
+                    None
+                );
+
+                // The oldest message is pruned, and the last page says so
+                tokio::time::sleep(time::Duration::from_millis(150)).await;
+                let history = hub.history(room_id, None, 10).await.unwrap();
+                let bodies: Vec<&str> = history
+                    .messages
+                    .iter()
+                    .map(|message| message.body.as_str())
+                    .collect();
+                assert_eq!(bodies, vec!["Two", "Three"]);
+                assert_eq!(history.cursor, None);
+                assert_eq!(history.pruned_until, Some(posted_at[0]));
+
+                // Earlier pages are not marked
+                let history = hub.history(room_id, None, 1).await.unwrap();
+                assert!(history.cursor.is_some());
+                assert_eq!(history.pruned_until, None);
+                assert_eq!(hub.metrics().messages_pruned.get(), 1);
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn reactions() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in &[(john_id, "John"), (jane_id, "Jane")] {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                // Skip join notifications
+                for _ in 0..2 {
+                    john.recv().await.unwrap();
+                }
+                jane.recv().await.unwrap();
+
+                sender
+                    .send(InputParcel::new(
+                        john_id,
                         Input::Post(PostInput {
+                            room_id,
                             body: String::from("Hello"),
+                            nonce: None,
+                            reply_to: None,
                         }),
                     ))
                     .unwrap();
-                let output = subscription.recv().await.unwrap().output;
-                if let Output::Posted(posted) = output {
-                    assert_eq!(posted.message.body, "Hello");
-                    assert_eq!(posted.message.user.id, user.id);
-                    assert_eq!(posted.message.user.name, user.name);
-                } else {
-                    panic!("Expected Output::Posted got {:?}", output);
+                let message_id = match john.recv().await {
+                    Some(Output::Posted(posted)) => posted.message.id,
+                    output => panic!("Expected Output::Posted got {:?}", output),
+                };
+                jane.recv().await.unwrap();
+
+                let react = |client_id, emoji: &str, added| {
+                    let input = ReactInput {
+                        room_id,
+                        message_id,
+                        emoji: String::from(emoji),
+                    };
+                    let input = if added {
+                        Input::React(input)
+                    } else {
+                        Input::Unreact(input)
+                    };
+                    sender.send(InputParcel::new(client_id, input)).unwrap();
+                };
+                let changed = |reactions| {
+                    Some(Output::ReactionsChanged(ReactionsChangedOutput::new(
+                        room_id, message_id, reactions,
+                    )))
+                };
+
+                react(jane_id, "👍", true);
+                let reactions = vec![ReactionOutput::new("👍", vec![jane_id])];
+                assert_eq!(jane.recv().await, changed(reactions.clone()));
+                assert_eq!(john.recv().await, changed(reactions.clone()));
+                // Reacting twice changes nothing, so only Jane hears back
+                react(jane_id, "👍", true);
+                assert_eq!(jane.recv().await, changed(reactions));
+
+                react(john_id, "ok", false);
+                assert_eq!(
+                    john.recv().await,
+                    Some(Output::Error(OutputError::InvalidEmoji))
+                );
+                react(john_id, "👍", true);
+                let mut user_ids = vec![john_id, jane_id];
+                user_ids.sort_unstable();
+                let reactions = vec![ReactionOutput::new("👍", user_ids)];
+                assert_eq!(john.recv().await, changed(reactions.clone()));
+                assert_eq!(jane.recv().await, changed(reactions));
+
+                react(jane_id, "👍", false);
+                let reactions = vec![ReactionOutput::new("👍", vec![john_id])];
+                assert_eq!(jane.recv().await, changed(reactions.clone()));
+                assert_eq!(john.recv().await, changed(reactions.clone()));
+
+                // History shows where reactions stand
+                let history = hub.history(room_id, None, 10).await.unwrap();
+                assert_eq!(history.messages[0].reactions, reactions);
+                assert!(john.is_empty());
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn threads() {
+        let hub = Hub::new(HubOptions::default());
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let john_id = Uuid::new_v4();
+                let jane_id = Uuid::new_v4();
+                let john = connect(&hub, &sender, john_id, None).await;
+                let jane = connect(&hub, &sender, jane_id, None).await;
+                // Carol's client knows nothing of threads
+                let carol_id = Uuid::new_v4();
+                let carol = hub.connect(carol_id, None);
+                sender
+                    .send(InputParcel::new(
+                        carol_id,
+                        Input::Hello(HelloInput {
+                            version: PROTOCOL_VERSION,
+                            capabilities: vec![String::from("history")],
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(carol.recv().await, Some(Output::Hello(_))));
+                let room_id = hub.default_room_id();
+
+                for (client_id, name) in
+                    &[(john_id, "John"), (jane_id, "Jane"), (carol_id, "Carol")]
+                {
+                    sender
+                        .send(InputParcel::new(
+                            *client_id,
+                            Input::Join(JoinInput {
+                                name: String::from(*name),
+                                credential: None,
+                            }),
+                        ))
+                        .unwrap();
+                }
+                // Skip join notifications
+                for _ in 0..3 {
+                    john.recv().await.unwrap();
+                }
+                for _ in 0..2 {
+                    jane.recv().await.unwrap();
                 }
+                carol.recv().await.unwrap();
 
-                return;
+                let post = |client_id, body: &str, reply_to| {
+                    sender
+                        .send(InputParcel::new(
+                            client_id,
+                            Input::Post(PostInput {
+                                room_id,
+                                body: String::from(body),
+                                nonce: None,
+                                reply_to,
+                            }),
+                        ))
+                        .unwrap();
+                };
+                let fetch_thread = |client_id, message_id| {
+                    sender
+                        .send(InputParcel::new(
+                            client_id,
+                            Input::FetchThread(FetchThreadInput { message_id }),
+                        ))
+                        .unwrap();
+                };
+
+                post(john_id, "Root", None);
+                let root_id = match john.recv().await {
+                    Some(Output::Posted(posted)) => posted.message.id,
+                    output => panic!("Expected Output::Posted got {:?}", output),
+                };
+                jane.recv().await.unwrap();
+                carol.recv().await.unwrap();
+                let replies_changed = |reply_count| {
+                    Some(Output::RepliesChanged(RepliesChangedOutput::new(
+                        room_id,
+                        root_id,
+                        reply_count,
+                    )))
+                };
+
+                post(jane_id, "Reply", Some(root_id));
+                let reply_id = match jane.recv().await {
+                    Some(Output::Posted(posted)) => {
+                        assert_eq!(posted.message.reply_to, Some(root_id));
+                        assert_eq!(posted.message.thread_root, Some(root_id));
+                        posted.message.id
+                    }
+                    output => panic!("Expected Output::Posted got {:?}", output),
+                };
+                // Replies go to those that understand threads, everyone else
+                // only hears how many there are
+                assert!(matches!(john.recv().await, Some(Output::UserReplied(_))));
+                assert_eq!(john.recv().await, replies_changed(1));
+                assert_eq!(carol.recv().await, replies_changed(1));
+
+                // A reply to a reply stays in the same thread
+                post(john_id, "Nested", Some(reply_id));
+                match john.recv().await {
+                    Some(Output::Posted(posted)) => {
+                        assert_eq!(posted.message.reply_to, Some(reply_id));
+                        assert_eq!(posted.message.thread_root, Some(root_id));
+                    }
+                    output => panic!("Expected Output::Posted got {:?}", output),
+                }
+                assert!(matches!(jane.recv().await, Some(Output::UserReplied(_))));
+                assert_eq!(jane.recv().await, replies_changed(2));
+                assert_eq!(carol.recv().await, replies_changed(2));
+
+                post(john_id, "Lost", Some(Uuid::new_v4()));
+                assert_eq!(
+                    john.recv().await,
+                    Some(Output::Error(OutputError::MessageNotFound))
+                );
+
+                // Replies stay out of the room's history
+                let history = hub.history(room_id, None, 10).await.unwrap();
+                assert_eq!(history.messages.len(), 1);
+                assert_eq!(history.messages[0].id, root_id);
+                assert_eq!(history.messages[0].reply_count, 2);
+
+                // Any message of the thread fetches all of it
+                fetch_thread(jane_id, reply_id);
+                match jane.recv().await {
+                    Some(Output::Thread(thread)) => {
+                        assert_eq!(thread.room_id, room_id);
+                        assert_eq!(thread.root_id, root_id);
+                        assert_eq!(thread.root.unwrap().body, "Root");
+                        let bodies: Vec<&str> = thread
+                            .replies
+                            .iter()
+                            .map(|message| message.body.as_str())
+                            .collect();
+                        assert_eq!(bodies, vec!["Reply", "Nested"]);
+                    }
+                    output => panic!("Expected Output::Thread got {:?}", output),
+                }
+
+                fetch_thread(jane_id, Uuid::new_v4());
+                assert_eq!(
+                    jane.recv().await,
+                    Some(Output::Error(OutputError::MessageNotFound))
+                );
+
+                // Deleting a reply takes it out of the count
+                sender
+                    .send(InputParcel::new(
+                        jane_id,
+                        Input::DeleteMessage(DeleteMessageInput {
+                            room_id,
+                            message_id: reply_id,
+                        }),
+                    ))
+                    .unwrap();
+                for outbox in &[&jane, &john, &carol] {
+                    assert!(matches!(
+                        outbox.recv().await,
+                        Some(Output::MessageDeleted(_))
+                    ));
+                    assert_eq!(outbox.recv().await, replies_changed(1));
+                }
+                let history = hub.history(room_id, None, 10).await.unwrap();
+                assert_eq!(history.messages[0].reply_count, 1);
+                assert!(john.is_empty());
+                assert!(carol.is_empty());
+            };
+            tokio::select! {
+              _ = hub.run(receiver) => {},
+              _ = case => {},
+            }
+        });
+    }
+
+    #[test]
+    fn alive() {
+        let hub = Hub::new(HubOptions {
+            alive_interval: Some(time::Duration::from_millis(10)),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let case = async {
+                let client_id = Uuid::new_v4();
+                let outbox = connect(&hub, &sender, client_id, None).await;
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::Join(JoinInput {
+                            name: String::from("John"),
+                            credential: None,
+                        }),
+                    ))
+                    .unwrap();
+                assert!(matches!(outbox.recv().await, Some(Output::Joined(_))));
+
+                // Keep-alives are not numbered, so they never crowd out the journal
+                for _ in 0..2 {
+                    let parcel = outbox.recv_parcel().await.unwrap();
+                    assert_eq!(parcel.output, Output::Alive);
+                    assert_eq!(parcel.seq, None);
+                }
+                sender
+                    .send(InputParcel::new(
+                        client_id,
+                        Input::CatchUp(CatchUpInput { since_seq: 0 }),
+                    ))
+                    .unwrap();
+                loop {
+                    match outbox.recv().await {
+                        Some(Output::Alive) => continue,
+                        output => {
+                            assert_eq!(output, Some(Output::CaughtUp(CaughtUpOutput::new(0))));
+                            break;
+                        }
+                    }
+                }
             };
             tokio::select! {
               _ = hub.run(receiver) => {},
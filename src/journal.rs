@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use crate::proto::{Output, OutputParcel};
+
+/// Numbers the outputs sent to a single user and keeps the latest ones, so
+/// that a client can catch up on what it missed while it was away.
+pub struct Journal {
+    capacity: usize,
+    last_seq: u64,
+    parcels: VecDeque<OutputParcel>,
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Journal {
+            capacity,
+            last_seq: 0,
+            parcels: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Gives the output the next sequence number and keeps it, forgetting the
+    /// oldest output once the journal is full.
+    pub fn record(&mut self, output: Output) -> OutputParcel {
+        self.last_seq += 1;
+        let parcel = OutputParcel::new(Some(self.last_seq), output);
+        if self.capacity > 0 {
+            if self.parcels.len() == self.capacity {
+                self.parcels.pop_front();
+            }
+            self.parcels.push_back(parcel.clone());
+        }
+        parcel
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Every output recorded after `seq`, or `None` if some of them are
+    /// already forgotten or `seq` was never handed out.
+    pub fn since(&self, seq: u64) -> Option<Vec<OutputParcel>> {
+        let forgotten_seq = self.last_seq - self.parcels.len() as u64;
+        if seq < forgotten_seq || seq > self.last_seq {
+            return None;
+        }
+        let skipped = (seq - forgotten_seq) as usize;
+        Some(self.parcels.iter().skip(skipped).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::Journal;
+    use crate::proto::{Output, OutputError};
+
+    #[test]
+    fn since() {
+        let mut journal = Journal::new(2);
+        assert_eq!(journal.since(0), Some(Vec::new()));
+
+        assert_eq!(journal.record(Output::Alive).seq, Some(1));
+        assert_eq!(
+            journal.record(Output::Error(OutputError::Muted)).seq,
+            Some(2)
+        );
+        assert_eq!(journal.since(0).unwrap().len(), 2);
+        let parcels = journal.since(1).unwrap();
+        assert_eq!(parcels.len(), 1);
+        assert_eq!(parcels[0].seq, Some(2));
+        assert_eq!(parcels[0].output, Output::Error(OutputError::Muted));
+
+        // The first output is forgotten
+        journal.record(Output::Alive);
+        assert_eq!(journal.last_seq(), 3);
+        assert_eq!(journal.since(0), None);
+        assert_eq!(journal.since(1).unwrap().len(), 2);
+        assert_eq!(journal.since(3), Some(Vec::new()));
+        assert_eq!(journal.since(4), None);
+    }
+}
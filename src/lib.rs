@@ -1,9 +1,19 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod api;
+pub mod auth;
 pub mod client;
+pub mod codec;
+pub mod config;
 pub mod error;
 pub mod hub;
+pub mod journal;
+pub mod limiter;
+pub mod metrics;
 pub mod model;
+pub mod outbox;
 pub mod proto;
 pub mod server;
+pub mod storage;
+pub mod tls;
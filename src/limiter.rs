@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Token bucket holding up to `burst` tokens, refilled by one every `interval`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<Uuid, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token from the key's bucket, or returns how long until one is
+    /// available.
+    pub fn check(&self, key: Uuid) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn remove(&self, key: Uuid) {
+        self.buckets.lock().unwrap().remove(&key);
+    }
+
+    fn check_at(&self, key: Uuid, now: Instant) -> Result<(), Duration> {
+        let burst = f64::from(self.limit.burst);
+        let interval = self.limit.interval.as_secs_f64();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+
+        // Refill for the time passed since the last check
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed / interval).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) * interval))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+    use uuid::Uuid;
+
+    use crate::limiter::{RateLimit, RateLimiter};
+
+    #[test]
+    fn refill() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 2,
+            interval: Duration::from_secs(10),
+        });
+        let key = Uuid::new_v4();
+        let start = Instant::now();
+        let retry_after_ms = |now| {
+            limiter
+                .check_at(key, now)
+                .map_err(|retry_after| retry_after.as_millis())
+        };
+
+        assert_eq!(retry_after_ms(start), Ok(()));
+        assert_eq!(retry_after_ms(start), Ok(()));
+        assert_eq!(retry_after_ms(start), Err(10_000));
+        // Other keys have their own bucket
+        assert_eq!(limiter.check_at(Uuid::new_v4(), start), Ok(()));
+
+        assert_eq!(retry_after_ms(start + Duration::from_secs(4)), Err(6_000));
+        assert_eq!(retry_after_ms(start + Duration::from_secs(10)), Ok(()));
+        assert!(retry_after_ms(start + Duration::from_secs(10)).is_err());
+    }
+}
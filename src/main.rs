@@ -1,9 +1,29 @@
+use std::io;
+
+use rusty_chat::auth::password;
+use rusty_chat::config::Config;
 use rusty_chat::server::Server;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let server = Server::new(8080);
+    let config = Config::load().expect("failed to load configuration");
+    if config.hash_password {
+        let mut password = String::new();
+        io::stdin()
+            .read_line(&mut password)
+            .expect("failed to read password");
+        let hash = password::hash_password(password.trim_end_matches(&['\r', '\n'][..]))
+            .expect("failed to hash password");
+        println!("{}", hash);
+        return;
+    }
+    let storage = config.storage().expect("failed to open storage");
+    let server_options = config
+        .server_options()
+        .expect("failed to load configuration");
+    let hub_options = config.hub_options().expect("failed to load configuration");
+    let server = Server::with_storage(server_options, hub_options, storage);
     server.run().await;
 }
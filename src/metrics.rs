@@ -0,0 +1,97 @@
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+
+/// Counters and gauges describing a running hub, in Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    pub connected_clients: IntGauge,
+    pub joined_users: IntGauge,
+    pub messages_posted: IntCounter,
+    /// Messages dropped by the retention policy.
+    pub messages_pruned: IntCounter,
+    /// Errors sent back to clients, by `OutputError` code.
+    pub rejected_inputs: IntCounterVec,
+    /// Outputs that could not be queued because a client fell behind.
+    pub lag_events: IntCounter,
+    /// Seconds spent in `Hub::process`, by input type.
+    pub input_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let connected_clients =
+            IntGauge::new("chat_connected_clients", "Open client connections").unwrap();
+        let joined_users = IntGauge::new("chat_joined_users", "Users that have joined").unwrap();
+        let messages_posted =
+            IntCounter::new("chat_messages_posted_total", "Messages posted to rooms").unwrap();
+        let messages_pruned = IntCounter::new(
+            "chat_messages_pruned_total",
+            "Messages dropped by the retention policy",
+        )
+        .unwrap();
+        let rejected_inputs = IntCounterVec::new(
+            Opts::new(
+                "chat_rejected_inputs_total",
+                "Inputs answered with an error",
+            ),
+            &["code"],
+        )
+        .unwrap();
+        let lag_events = IntCounter::new(
+            "chat_lag_events_total",
+            "Outputs dropped or connections closed because a client fell behind",
+        )
+        .unwrap();
+        let input_latency = HistogramVec::new(
+            // From 50µs up to about a second
+            HistogramOpts::new("chat_input_latency_seconds", "Time spent processing inputs")
+                .buckets(exponential_buckets(0.00005, 4.0, 8).unwrap()),
+            &["input"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(connected_clients.clone()))
+            .unwrap();
+        registry.register(Box::new(joined_users.clone())).unwrap();
+        registry
+            .register(Box::new(messages_posted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(messages_pruned.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rejected_inputs.clone()))
+            .unwrap();
+        registry.register(Box::new(lag_events.clone())).unwrap();
+        registry.register(Box::new(input_latency.clone())).unwrap();
+
+        Metrics {
+            registry,
+            connected_clients,
+            joined_users,
+            messages_posted,
+            messages_pruned,
+            rejected_inputs,
+            lag_events,
+            input_latency,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,17 +1,308 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
 use crate::model::message::Message;
 
-#[derive(Default)]
+/// Messages kept per feed when no capacity is given.
+pub const FEED_CAPACITY: usize = 100_000;
+
+/// Newest messages of a feed, in the order they were posted.
+///
+/// Messages are only ever appended, so posting never reorders the feed. Once
+/// the feed is full the oldest message is dropped to make room. Each message
+/// is indexed by its position since the feed was created, which stays valid
+/// however many messages are dropped in front of it.
+///
+/// Replies live in the same feed as everything else, but are left out of
+/// pages of history and fetched by thread instead.
 pub struct Feed {
-    messages: Vec<Message>,
+    capacity: usize,
+    // Position of the oldest kept message
+    first_position: u64,
+    messages: VecDeque<Message>,
+    positions: HashMap<Uuid, u64>,
+    // Positions of the kept replies of each thread, oldest first
+    threads: HashMap<Uuid, VecDeque<u64>>,
+    // Total length of the kept message bodies
+    bytes: usize,
+    pruned_until: Option<DateTime<Utc>>,
 }
 
 impl Feed {
+    pub fn new(capacity: usize) -> Self {
+        Feed {
+            capacity,
+            first_position: 0,
+            messages: VecDeque::new(),
+            positions: HashMap::new(),
+            threads: HashMap::new(),
+            bytes: 0,
+            pruned_until: None,
+        }
+    }
+
     pub fn add_message(&mut self, message: Message) {
-        self.messages.push(message);
-        self.messages.sort_by_key(|message| message.created_at)
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.remove_oldest();
+        }
+        let position = self.first_position + self.messages.len() as u64;
+        self.positions.insert(message.id, position);
+        if let Some(root_id) = message.thread_root {
+            if let Some(index) = self.index(root_id) {
+                self.messages[index].reply_count += 1;
+            }
+            self.threads.entry(root_id).or_default().push_back(position);
+        }
+        self.bytes += message.body.len();
+        self.messages.push_back(message);
+    }
+
+    /// Drops the oldest message, remembering that history before it is gone.
+    pub fn remove_oldest(&mut self) -> Option<Message> {
+        let message = self.messages.pop_front()?;
+        self.positions.remove(&message.id);
+        // A root is older than its replies, so there is no count left to fix
+        if let Some(root_id) = message.thread_root {
+            if let Some(replies) = self.threads.get_mut(&root_id) {
+                replies.pop_front();
+                if replies.is_empty() {
+                    self.threads.remove(&root_id);
+                }
+            }
+        }
+        self.first_position += 1;
+        self.bytes -= message.body.len();
+        self.pruned_until = Some(message.created_at);
+        Some(message)
+    }
+
+    pub fn edit_message(&mut self, id: Uuid, body: &str, edited_at: DateTime<Utc>) -> bool {
+        let index = if let Some(index) = self.index(id) {
+            index
+        } else {
+            return false;
+        };
+        let message = &mut self.messages[index];
+        self.bytes = self.bytes - message.body.len() + body.len();
+        message.edit(body, edited_at);
+        true
+    }
+
+    pub fn delete_message(&mut self, id: Uuid) -> bool {
+        let index = if let Some(index) = self.index(id) {
+            index
+        } else {
+            return false;
+        };
+        let message = &mut self.messages[index];
+        let counted_root = if message.deleted {
+            None
+        } else {
+            message.thread_root
+        };
+        self.bytes -= message.body.len();
+        message.delete();
+        // Deleted replies no longer count towards their thread
+        if let Some(root_index) = counted_root.and_then(|root_id| self.index(root_id)) {
+            let root = &mut self.messages[root_index];
+            root.reply_count = root.reply_count.saturating_sub(1);
+        }
+        true
+    }
+
+    /// Adds or removes a user's reaction, returning whether anything changed.
+    pub fn react(&mut self, id: Uuid, user_id: Uuid, emoji: &str, added: bool) -> bool {
+        let index = if let Some(index) = self.index(id) {
+            index
+        } else {
+            return false;
+        };
+        let message = &mut self.messages[index];
+        if added {
+            message.react(user_id, emoji)
+        } else {
+            message.unreact(user_id, emoji)
+        }
+    }
+
+    /// Remembers that history up to `until` is gone, e.g. when restoring a
+    /// feed that was pruned before.
+    pub fn mark_pruned(&mut self, until: DateTime<Utc>) {
+        self.pruned_until = self.pruned_until.max(Some(until));
     }
 
-    pub fn messages_iter(&self) -> impl Iterator<Item = &Message> {
+    pub fn message(&self, id: Uuid) -> Option<&Message> {
+        self.messages.get(self.index(id)?)
+    }
+
+    /// Every kept message, replies included, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Message> {
         self.messages.iter()
     }
+
+    pub fn oldest(&self) -> Option<&Message> {
+        self.messages.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Creation time of the newest message dropped so far, if any.
+    pub fn pruned_until(&self) -> Option<DateTime<Utc>> {
+        self.pruned_until
+    }
+
+    /// Returns up to `limit` of the newest messages outside of threads
+    /// before the message with id `before`, or nothing if that message is not
+    /// kept.
+    pub fn messages_before(
+        &self,
+        before: Option<Uuid>,
+        limit: usize,
+    ) -> impl Iterator<Item = &Message> {
+        let end = match before {
+            Some(id) => self.index(id).unwrap_or(0),
+            None => self.messages.len(),
+        };
+        let mut page: Vec<&Message> = self
+            .messages
+            .range(..end)
+            .rev()
+            .filter(|message| message.thread_root.is_none())
+            .take(limit)
+            .collect();
+        page.reverse();
+        page.into_iter()
+    }
+
+    /// Returns the kept replies in the thread started by the message with id
+    /// `root_id`, oldest first.
+    pub fn replies(&self, root_id: Uuid) -> impl Iterator<Item = &Message> {
+        self.threads
+            .get(&root_id)
+            .into_iter()
+            .flatten()
+            .filter_map(move |position| {
+                self.messages.get((position - self.first_position) as usize)
+            })
+    }
+
+    fn index(&self, id: Uuid) -> Option<usize> {
+        let position = self.positions.get(&id)?;
+        Some((position - self.first_position) as usize)
+    }
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Self::new(FEED_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use crate::model::feed::Feed;
+    use crate::model::message::Message;
+    use crate::model::user::User;
+
+    #[test]
+    fn drop_oldest() {
+        let user = User::new(Uuid::new_v4(), "John");
+        let mut feed = Feed::new(3);
+        let ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        for (index, id) in ids.iter().enumerate() {
+            feed.add_message(Message::new(
+                *id,
+                user.clone(),
+                &index.to_string(),
+                Utc::now(),
+            ));
+        }
+
+        assert_eq!(feed.len(), 3);
+        assert_eq!(feed.bytes(), 3);
+        assert!(feed.message(ids[1]).is_none());
+        assert_eq!(feed.message(ids[2]).unwrap().body, "2");
+        assert!(feed.pruned_until().is_some());
+        assert!(feed.delete_message(ids[4]));
+        assert!(feed.message(ids[4]).unwrap().deleted);
+        assert!(feed.edit_message(ids[3], "three", Utc::now()));
+        assert_eq!(feed.bytes(), 6);
+        assert!(!feed.delete_message(ids[0]));
+
+        let bodies = |before, limit| -> Vec<String> {
+            feed.messages_before(before, limit)
+                .map(|message| message.body.clone())
+                .collect()
+        };
+        assert_eq!(bodies(None, 2), vec!["three", ""]);
+        assert_eq!(bodies(Some(ids[4]), 10), vec!["2", "three"]);
+        // Nothing comes before a dropped message
+        assert!(bodies(Some(ids[0]), 10).is_empty());
+    }
+
+    #[test]
+    fn threads() {
+        let user = User::new(Uuid::new_v4(), "John");
+        let mut feed = Feed::new(4);
+        let root = Message::new(Uuid::new_v4(), user.clone(), "Root", Utc::now());
+        let root_id = root.id;
+        feed.add_message(root);
+        let reply = |body| {
+            Message::new(Uuid::new_v4(), user.clone(), body, Utc::now()).in_thread(root_id, root_id)
+        };
+        feed.add_message(reply("First"));
+        feed.add_message(Message::new(
+            Uuid::new_v4(),
+            user.clone(),
+            "Other",
+            Utc::now(),
+        ));
+        feed.add_message(reply("Second"));
+
+        assert_eq!(feed.message(root_id).unwrap().reply_count, 2);
+        let bodies: Vec<&str> = feed
+            .messages_before(None, 10)
+            .map(|message| message.body.as_str())
+            .collect();
+        assert_eq!(bodies, vec!["Root", "Other"]);
+        let replies = |feed: &Feed| -> Vec<String> {
+            feed.replies(root_id)
+                .map(|message| message.body.clone())
+                .collect()
+        };
+        assert_eq!(replies(&feed), vec!["First", "Second"]);
+
+        // Deleted replies stay in the thread, but are not counted
+        let first_id = feed.replies(root_id).next().unwrap().id;
+        for _ in 0..2 {
+            assert!(feed.delete_message(first_id));
+            assert_eq!(feed.message(root_id).unwrap().reply_count, 1);
+        }
+        assert_eq!(replies(&feed), vec!["", "Second"]);
+
+        // Dropping the root and the first reply keeps the rest of the thread
+        feed.add_message(reply("Third"));
+        feed.add_message(reply("Fourth"));
+        assert!(feed.message(root_id).is_none());
+        assert_eq!(replies(&feed), vec!["Second", "Third", "Fourth"]);
+        assert!(feed.replies(Uuid::new_v4()).next().is_none());
+    }
 }
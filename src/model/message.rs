@@ -1,3 +1,5 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
 use chrono::prelude::*;
 use uuid::Uuid;
 
@@ -9,6 +11,17 @@ pub struct Message {
     pub user: User,
     pub body: String,
     pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted: bool,
+    /// Emoji each user reacted with.
+    pub reactions: HashMap<Uuid, BTreeSet<String>>,
+    /// Message this one replies to.
+    pub reply_to: Option<Uuid>,
+    /// First message of the thread this reply belongs to.
+    pub thread_root: Option<Uuid>,
+    /// Replies kept in the thread started by this message, not counting
+    /// deleted ones.
+    pub reply_count: usize,
 }
 
 impl Message {
@@ -18,6 +31,69 @@ impl Message {
             user,
             body: String::from(body),
             created_at,
+            edited_at: None,
+            deleted: false,
+            reactions: HashMap::new(),
+            reply_to: None,
+            thread_root: None,
+            reply_count: 0,
+        }
+    }
+
+    /// Makes the message a reply to `reply_to`, in the thread started by
+    /// `thread_root`.
+    pub fn in_thread(mut self, reply_to: Uuid, thread_root: Uuid) -> Self {
+        self.reply_to = Some(reply_to);
+        self.thread_root = Some(thread_root);
+        self
+    }
+
+    pub fn edit(&mut self, body: &str, edited_at: DateTime<Utc>) {
+        self.body = String::from(body);
+        self.edited_at = Some(edited_at);
+    }
+
+    // Deleted messages are kept as tombstones so history stays consistent
+    pub fn delete(&mut self) {
+        self.body.clear();
+        self.deleted = true;
+        self.reactions.clear();
+    }
+
+    /// Adds a reaction, returning `false` if the user already reacted with
+    /// the same emoji.
+    pub fn react(&mut self, user_id: Uuid, emoji: &str) -> bool {
+        self.reactions
+            .entry(user_id)
+            .or_default()
+            .insert(String::from(emoji))
+    }
+
+    /// Removes a reaction, returning `false` if there was none.
+    pub fn unreact(&mut self, user_id: Uuid, emoji: &str) -> bool {
+        let emojis = if let Some(emojis) = self.reactions.get_mut(&user_id) {
+            emojis
+        } else {
+            return false;
+        };
+        let removed = emojis.remove(emoji);
+        if emojis.is_empty() {
+            self.reactions.remove(&user_id);
+        }
+        removed
+    }
+
+    /// Users that reacted with each emoji, ordered by emoji.
+    pub fn reactions_by_emoji(&self) -> BTreeMap<&str, Vec<Uuid>> {
+        let mut by_emoji: BTreeMap<&str, Vec<Uuid>> = BTreeMap::new();
+        for (user_id, emojis) in &self.reactions {
+            for emoji in emojis {
+                by_emoji.entry(emoji).or_default().push(*user_id);
+            }
         }
+        by_emoji
+            .values_mut()
+            .for_each(|user_ids| user_ids.sort_unstable());
+        by_emoji
     }
 }
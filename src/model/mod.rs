@@ -1,3 +1,5 @@
 pub mod feed;
 pub mod message;
+pub mod room;
+pub mod session;
 pub mod user;
@@ -0,0 +1,19 @@
+use std::collections::HashSet;
+
+use uuid::Uuid;
+
+pub struct Room {
+    pub id: Uuid,
+    pub name: String,
+    pub users: HashSet<Uuid>,
+}
+
+impl Room {
+    pub fn new(id: Uuid, name: &str) -> Self {
+        Room {
+            id,
+            name: String::from(name),
+            users: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::proto::Capability;
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user_id: Uuid,
+    pub token: String,
+    pub client_id: Option<Uuid>,
+    pub disconnected_at: Option<Instant>,
+    /// What the last connection asked for, so that only outputs it would have
+    /// been sent are kept for it while it is away.
+    pub capabilities: Vec<Capability>,
+}
+
+impl Session {
+    pub fn new(user_id: Uuid, token: &str, client_id: Uuid) -> Self {
+        Session {
+            user_id,
+            token: String::from(token),
+            client_id: Some(client_id),
+            disconnected_at: None,
+            capabilities: Vec::new(),
+        }
+    }
+}
@@ -1,16 +1,32 @@
 use uuid::Uuid;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Member,
+    Moderator,
+}
+
 #[derive(Debug, Clone)]
 pub struct User {
     pub id: Uuid,
     pub name: String,
+    pub role: Role,
 }
 
 impl User {
     pub fn new(id: Uuid, name: &str) -> Self {
+        Self::with_role(id, name, Role::Member)
+    }
+
+    pub fn with_role(id: Uuid, name: &str, role: Role) -> Self {
         User {
             id,
             name: String::from(name),
+            role,
         }
     }
+
+    pub fn is_moderator(&self) -> bool {
+        self.role == Role::Moderator
+    }
 }
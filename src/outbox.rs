@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use log::warn;
+use tokio::sync::Notify;
+
+use crate::proto::{Output, OutputParcel};
+
+/// What to do when a client does not read its outputs fast enough.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LagPolicy {
+    /// Discard the oldest queued output to make room for the new one.
+    DropOldest,
+    /// Close the connection, leaving it to the client to resume.
+    #[default]
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    Lagged,
+    Kicked,
+    Banned,
+}
+
+impl CloseReason {
+    pub fn code(self) -> u16 {
+        match self {
+            // Try again later
+            CloseReason::Lagged => 1013,
+            // Policy violation
+            CloseReason::Kicked | CloseReason::Banned => 1008,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Lagged => "lagged",
+            CloseReason::Kicked => "kicked",
+            CloseReason::Banned => "banned",
+        }
+    }
+}
+
+struct OutboxState {
+    queue: VecDeque<OutputParcel>,
+    closed: Option<CloseReason>,
+}
+
+/// Bounded queue of outputs waiting to be written to a single client.
+pub struct Outbox {
+    capacity: usize,
+    lag_policy: LagPolicy,
+    state: Mutex<OutboxState>,
+    notify: Notify,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, lag_policy: LagPolicy) -> Self {
+        Outbox {
+            capacity,
+            lag_policy,
+            state: Mutex::new(OutboxState {
+                queue: VecDeque::with_capacity(capacity),
+                closed: None,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Queues an output, returning `false` if the client fell behind and the
+    /// lag policy kicked in.
+    pub fn push(&self, output: Output) -> bool {
+        self.push_parcel(OutputParcel::new(None, output))
+    }
+
+    pub fn push_parcel(&self, parcel: OutputParcel) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_some() {
+            return true;
+        }
+        let kept_up = state.queue.len() < self.capacity;
+        if !kept_up {
+            match self.lag_policy {
+                LagPolicy::DropOldest => {
+                    warn!("Outbox full, dropping oldest output");
+                    state.queue.pop_front();
+                }
+                LagPolicy::Disconnect => {
+                    warn!("Outbox full, disconnecting client");
+                    state.queue.clear();
+                    state.closed = Some(CloseReason::Lagged);
+                    drop(state);
+                    self.notify.notify_one();
+                    return false;
+                }
+            }
+        }
+        state.queue.push_back(parcel);
+        drop(state);
+        self.notify.notify_one();
+        kept_up
+    }
+
+    pub fn close(&self, reason: CloseReason) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_none() {
+            state.closed = Some(reason);
+        }
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next output, or returns `None` once the outbox is closed
+    /// and drained.
+    pub async fn recv(&self) -> Option<Output> {
+        self.recv_parcel().await.map(|parcel| parcel.output)
+    }
+
+    pub async fn recv_parcel(&self) -> Option<OutputParcel> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(parcel) = state.queue.pop_front() {
+                    return Some(parcel);
+                }
+                if state.closed.is_some() {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.state.lock().unwrap().closed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outbox::{CloseReason, LagPolicy, Outbox};
+    use crate::proto::{Output, OutputError};
+
+    #[test]
+    fn drop_oldest() {
+        let outbox = Outbox::new(2, LagPolicy::DropOldest);
+        assert!(outbox.push(Output::Alive));
+        assert!(outbox.push(Output::Error(OutputError::NotJoined)));
+        assert!(!outbox.push(Output::Error(OutputError::InvalidName)));
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            assert_eq!(
+                outbox.recv().await,
+                Some(Output::Error(OutputError::NotJoined))
+            );
+            assert_eq!(
+                outbox.recv().await,
+                Some(Output::Error(OutputError::InvalidName))
+            );
+        });
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.close_reason(), None);
+    }
+
+    #[test]
+    fn disconnect() {
+        let outbox = Outbox::new(2, LagPolicy::Disconnect);
+        assert!(outbox.push(Output::Alive));
+        assert!(outbox.push(Output::Alive));
+        assert!(!outbox.push(Output::Alive));
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            assert_eq!(outbox.recv().await, None);
+        });
+        assert_eq!(outbox.close_reason(), Some(CloseReason::Lagged));
+    }
+}
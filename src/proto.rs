@@ -1,14 +1,60 @@
+use std::net::IpAddr;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Newest protocol version, announced in `hello`.
+pub const PROTOCOL_VERSION: u32 = 5;
+/// Oldest protocol version still spoken, and the one assumed for clients that
+/// never say hello.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// First protocol version whose outputs carry a `seq`.
+pub const SEQ_PROTOCOL_VERSION: u32 = 3;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload", rename_all = "camelCase")]
 pub enum Input {
     #[serde(rename = "join")]
     Join(JoinInput),
+    #[serde(rename = "resume")]
+    Resume(ResumeInput),
     #[serde(rename = "post")]
     Post(PostInput),
+    #[serde(rename = "create-room")]
+    CreateRoom(CreateRoomInput),
+    #[serde(rename = "join-room")]
+    JoinRoom(JoinRoomInput),
+    #[serde(rename = "leave-room")]
+    LeaveRoom(LeaveRoomInput),
+    #[serde(rename = "list-rooms")]
+    ListRooms,
+    #[serde(rename = "direct-message")]
+    DirectMessage(DirectMessageInput),
+    #[serde(rename = "fetch-history")]
+    FetchHistory(FetchHistoryInput),
+    #[serde(rename = "edit-message")]
+    EditMessage(EditMessageInput),
+    #[serde(rename = "delete-message")]
+    DeleteMessage(DeleteMessageInput),
+    #[serde(rename = "typing")]
+    Typing(TypingInput),
+    #[serde(rename = "kick")]
+    Kick(KickInput),
+    #[serde(rename = "mute")]
+    Mute(MuteInput),
+    #[serde(rename = "ban")]
+    Ban(BanInput),
+    #[serde(rename = "hello")]
+    Hello(HelloInput),
+    #[serde(rename = "catch-up")]
+    CatchUp(CatchUpInput),
+    #[serde(rename = "react")]
+    React(ReactInput),
+    #[serde(rename = "unreact")]
+    Unreact(ReactInput),
+    #[serde(rename = "fetch-thread")]
+    FetchThread(FetchThreadInput),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -20,6 +66,8 @@ pub enum Output {
     Alive,
     #[serde(rename = "joined")]
     Joined(JoinedOutput),
+    #[serde(rename = "resumed")]
+    Resumed(ResumedOutput),
     #[serde(rename = "user-joined")]
     UserJoined(UserJoinedOutput),
     #[serde(rename = "user-left")]
@@ -28,6 +76,38 @@ pub enum Output {
     Posted(PostedOutput),
     #[serde(rename = "user-posted")]
     UserPosted(UserPostedOutput),
+    #[serde(rename = "room-created")]
+    RoomCreated(RoomCreatedOutput),
+    #[serde(rename = "left")]
+    Left(LeftOutput),
+    #[serde(rename = "rooms")]
+    Rooms(RoomsOutput),
+    #[serde(rename = "direct-message")]
+    DirectMessage(DirectMessageOutput),
+    #[serde(rename = "history")]
+    History(HistoryOutput),
+    #[serde(rename = "message-edited")]
+    MessageEdited(MessageEditedOutput),
+    #[serde(rename = "message-deleted")]
+    MessageDeleted(MessageDeletedOutput),
+    #[serde(rename = "user-typing")]
+    UserTyping(UserTypingOutput),
+    #[serde(rename = "moderated")]
+    Moderated(ModeratedOutput),
+    #[serde(rename = "hello")]
+    Hello(HelloOutput),
+    #[serde(rename = "caught-up")]
+    CaughtUp(CaughtUpOutput),
+    #[serde(rename = "reactions-changed")]
+    ReactionsChanged(ReactionsChangedOutput),
+    #[serde(rename = "thread")]
+    Thread(ThreadOutput),
+    /// A reply, sent instead of `user-posted` so that clients without
+    /// threads keep them out of the main feed.
+    #[serde(rename = "user-replied")]
+    UserReplied(UserPostedOutput),
+    #[serde(rename = "replies-changed")]
+    RepliesChanged(RepliesChangedOutput),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -39,8 +119,86 @@ pub enum OutputError {
     InvalidName,
     #[serde(rename = "not-joined")]
     NotJoined,
+    /// The connection already speaks for a user, and cannot take on another.
+    #[serde(rename = "already-joined")]
+    AlreadyJoined,
     #[serde(rename = "invalid-message-body")]
     InvalidMessageBody,
+    #[serde(rename = "room-name-taken")]
+    RoomNameTaken,
+    #[serde(rename = "invalid-room-name")]
+    InvalidRoomName,
+    #[serde(rename = "room-not-found")]
+    RoomNotFound,
+    #[serde(rename = "not-in-room")]
+    NotInRoom,
+    #[serde(rename = "unknown-recipient")]
+    UnknownRecipient,
+    #[serde(rename = "internal-error")]
+    Internal,
+    #[serde(rename = "message-not-found")]
+    MessageNotFound,
+    #[serde(rename = "not-message-author")]
+    NotMessageAuthor,
+    #[serde(rename = "invalid-session")]
+    InvalidSession,
+    #[serde(rename = "not-moderator")]
+    NotModerator,
+    #[serde(rename = "user-not-found")]
+    UserNotFound,
+    #[serde(rename = "muted")]
+    Muted,
+    #[serde(rename = "banned")]
+    Banned,
+    #[serde(rename = "rate-limited", rename_all = "camelCase")]
+    RateLimited { retry_after_ms: u64 },
+    #[serde(rename = "unauthorized")]
+    Unauthorized,
+    #[serde(rename = "malformed-input")]
+    MalformedInput,
+    #[serde(rename = "unsupported-version", rename_all = "camelCase")]
+    UnsupportedVersion { min_version: u32, max_version: u32 },
+    /// Some of the outputs to catch up on are no longer kept, so the client
+    /// has to fetch what it needs afresh and carry on from `last_seq`.
+    #[serde(rename = "catch-up-unavailable", rename_all = "camelCase")]
+    CatchUpUnavailable { last_seq: u64 },
+    #[serde(rename = "invalid-emoji")]
+    InvalidEmoji,
+    #[serde(rename = "too-many-reactions")]
+    TooManyReactions,
+}
+
+/// Group of outputs beyond the original protocol, which clients are only sent
+/// unprompted if they declared it in `hello`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Capability {
+    /// Rooms other than the default one.
+    #[serde(rename = "rooms")]
+    Rooms,
+    #[serde(rename = "direct-messages")]
+    DirectMessages,
+    /// Paging through, editing and deleting messages.
+    #[serde(rename = "history")]
+    History,
+    #[serde(rename = "typing")]
+    Typing,
+    #[serde(rename = "moderation")]
+    Moderation,
+    #[serde(rename = "reactions")]
+    Reactions,
+    /// Replies to messages, and fetching whole threads.
+    #[serde(rename = "threads")]
+    Threads,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ModerationAction {
+    #[serde(rename = "kick")]
+    Kick,
+    #[serde(rename = "mute")]
+    Mute,
+    #[serde(rename = "ban")]
+    Ban,
 }
 
 #[derive(Debug, Clone)]
@@ -49,21 +207,185 @@ pub struct InputParcel {
     pub input: Input,
 }
 
+/// Output as written to a client. Outputs sent to a user unprompted are
+/// numbered, one after another for each user, so that the client can tell
+/// when it missed some.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputParcel {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub output: Output,
+}
+
+impl Input {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Input::Join(_) => "join",
+            Input::Resume(_) => "resume",
+            Input::Post(_) => "post",
+            Input::CreateRoom(_) => "create-room",
+            Input::JoinRoom(_) => "join-room",
+            Input::LeaveRoom(_) => "leave-room",
+            Input::ListRooms => "list-rooms",
+            Input::DirectMessage(_) => "direct-message",
+            Input::FetchHistory(_) => "fetch-history",
+            Input::EditMessage(_) => "edit-message",
+            Input::DeleteMessage(_) => "delete-message",
+            Input::Typing(_) => "typing",
+            Input::Kick(_) => "kick",
+            Input::Mute(_) => "mute",
+            Input::Ban(_) => "ban",
+            Input::Hello(_) => "hello",
+            Input::CatchUp(_) => "catch-up",
+            Input::React(_) => "react",
+            Input::Unreact(_) => "unreact",
+            Input::FetchThread(_) => "fetch-thread",
+        }
+    }
+
+    /// Whether the input counts against the client's rate limit, which is
+    /// the case for everything but reads.
+    pub fn is_rate_limited(&self) -> bool {
+        match self {
+            Input::Join(_)
+            | Input::Resume(_)
+            | Input::Post(_)
+            | Input::CreateRoom(_)
+            | Input::JoinRoom(_)
+            | Input::LeaveRoom(_)
+            | Input::DirectMessage(_)
+            | Input::EditMessage(_)
+            | Input::DeleteMessage(_)
+            | Input::Typing(_)
+            | Input::Kick(_)
+            | Input::Mute(_)
+            | Input::Ban(_)
+            | Input::React(_)
+            | Input::Unreact(_) => true,
+            Input::ListRooms
+            | Input::FetchHistory(_)
+            | Input::Hello(_)
+            | Input::CatchUp(_)
+            | Input::FetchThread(_) => false,
+        }
+    }
+}
+
+impl Output {
+    /// Capability a client must have declared to be sent this output without
+    /// asking for it.
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Output::Error(_)
+            | Output::Alive
+            | Output::Joined(_)
+            | Output::Resumed(_)
+            | Output::UserJoined(_)
+            | Output::UserLeft(_)
+            | Output::Posted(_)
+            | Output::UserPosted(_)
+            | Output::Hello(_)
+            | Output::CaughtUp(_) => None,
+            Output::RoomCreated(_) | Output::Left(_) | Output::Rooms(_) => Some(Capability::Rooms),
+            Output::DirectMessage(_) => Some(Capability::DirectMessages),
+            Output::History(_)
+            | Output::MessageEdited(_)
+            | Output::MessageDeleted(_)
+            | Output::RepliesChanged(_) => Some(Capability::History),
+            Output::UserTyping(_) => Some(Capability::Typing),
+            Output::Moderated(_) => Some(Capability::Moderation),
+            Output::ReactionsChanged(_) => Some(Capability::Reactions),
+            Output::Thread(_) | Output::UserReplied(_) => Some(Capability::Threads),
+        }
+    }
+}
+
+impl OutputError {
+    pub fn code(self) -> &'static str {
+        match self {
+            OutputError::NameTaken => "name-taken",
+            OutputError::InvalidName => "invalid-name",
+            OutputError::NotJoined => "not-joined",
+            OutputError::AlreadyJoined => "already-joined",
+            OutputError::InvalidMessageBody => "invalid-message-body",
+            OutputError::RoomNameTaken => "room-name-taken",
+            OutputError::InvalidRoomName => "invalid-room-name",
+            OutputError::RoomNotFound => "room-not-found",
+            OutputError::NotInRoom => "not-in-room",
+            OutputError::UnknownRecipient => "unknown-recipient",
+            OutputError::Internal => "internal-error",
+            OutputError::MessageNotFound => "message-not-found",
+            OutputError::NotMessageAuthor => "not-message-author",
+            OutputError::InvalidSession => "invalid-session",
+            OutputError::NotModerator => "not-moderator",
+            OutputError::UserNotFound => "user-not-found",
+            OutputError::Muted => "muted",
+            OutputError::Banned => "banned",
+            OutputError::RateLimited { .. } => "rate-limited",
+            OutputError::Unauthorized => "unauthorized",
+            OutputError::MalformedInput => "malformed-input",
+            OutputError::UnsupportedVersion { .. } => "unsupported-version",
+            OutputError::CatchUpUnavailable { .. } => "catch-up-unavailable",
+            OutputError::InvalidEmoji => "invalid-emoji",
+            OutputError::TooManyReactions => "too-many-reactions",
+        }
+    }
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::Rooms,
+        Capability::DirectMessages,
+        Capability::History,
+        Capability::Typing,
+        Capability::Moderation,
+        Capability::Reactions,
+        Capability::Threads,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Rooms => "rooms",
+            Capability::DirectMessages => "direct-messages",
+            Capability::History => "history",
+            Capability::Typing => "typing",
+            Capability::Moderation => "moderation",
+            Capability::Reactions => "reactions",
+            Capability::Threads => "threads",
+        }
+    }
+
+    /// First protocol version that offers the capability.
+    pub fn since(self) -> u32 {
+        match self {
+            Capability::Rooms
+            | Capability::DirectMessages
+            | Capability::History
+            | Capability::Typing
+            | Capability::Moderation => 2,
+            Capability::Reactions => 4,
+            Capability::Threads => 5,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Capability::ALL
+            .iter()
+            .copied()
+            .find(|capability| capability.name() == name)
+    }
+}
+
 impl InputParcel {
     pub fn new(client_id: Uuid, input: Input) -> Self {
         InputParcel { client_id, input }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct OutputParcel {
-    pub client_id: Uuid,
-    pub output: Output,
-}
-
 impl OutputParcel {
-    pub fn new(client_id: Uuid, output: Output) -> Self {
-        OutputParcel { client_id, output }
+    pub fn new(seq: Option<u64>, output: Output) -> Self {
+        OutputParcel { seq, output }
     }
 }
 
@@ -71,12 +393,143 @@ impl OutputParcel {
 #[serde(rename_all = "camelCase")]
 pub struct JoinInput {
     pub name: String,
+    /// Password or token proving the name belongs to the user, if the server
+    /// requires one.
+    pub credential: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeInput {
+    pub token: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PostInput {
+    /// The default room if left out, and always for version 1 clients, which
+    /// knew no other.
+    #[serde(default)]
+    pub room_id: Uuid,
     pub body: String,
+    /// Client-generated id for the post, so that retrying it does not post
+    /// the message twice.
+    pub nonce: Option<String>,
+    /// Message in the same room this one replies to.
+    pub reply_to: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateRoomInput {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinRoomInput {
+    pub room_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaveRoomInput {
+    pub room_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessageInput {
+    pub to: Uuid,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchHistoryInput {
+    pub room_id: Uuid,
+    pub before: Option<Uuid>,
+    /// Clamped to between 1 and 100 messages.
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EditMessageInput {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteMessageInput {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactInput {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub emoji: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchThreadInput {
+    /// Any message of the thread, its root or one of the replies.
+    pub message_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypingInput {
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KickInput {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MuteInput {
+    pub user_id: Uuid,
+    pub duration_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BanTarget {
+    Name(String),
+    Address(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BanInput {
+    pub target: BanTarget,
+    pub duration_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HelloInput {
+    pub version: u32,
+    /// Names of the capabilities the client understands, unknown ones are
+    /// ignored.
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CatchUpInput {
+    /// Last sequence number the client saw, or 0 to replay everything kept.
+    pub since_seq: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -86,6 +539,13 @@ pub struct UserOutput {
     pub name: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomOutput {
+    pub id: Uuid,
+    pub name: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageOutput {
@@ -93,40 +553,188 @@ pub struct MessageOutput {
     pub user: UserOutput,
     pub body: String,
     pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted: bool,
+    pub reactions: Vec<ReactionOutput>,
+    pub reply_to: Option<Uuid>,
+    /// First message of the thread, set on replies only.
+    pub thread_root: Option<Uuid>,
+    /// Replies still kept in the thread this message started.
+    pub reply_count: usize,
+}
+
+/// Everyone that reacted to a message with the same emoji.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionOutput {
+    pub emoji: String,
+    pub count: usize,
+    pub user_ids: Vec<Uuid>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct JoinedOutput {
+    pub room: RoomOutput,
     pub user: UserOutput,
     pub others: Vec<UserOutput>,
     pub messages: Vec<MessageOutput>,
+    pub cursor: Option<Uuid>,
+    /// Set on the oldest page if older messages were dropped by the retention
+    /// policy, to when the newest of them was posted.
+    pub pruned_until: Option<DateTime<Utc>>,
+    pub session_token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumedOutput {
+    pub user: UserOutput,
+    pub rooms: Vec<RoomOutput>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserJoinedOutput {
+    pub room_id: Uuid,
     pub user: UserOutput,
 }
 
 #[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserLeftOutput {
+    pub room_id: Uuid,
     pub user_id: Uuid,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PostedOutput {
+    pub room_id: Uuid,
     pub message: MessageOutput,
+    pub nonce: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserPostedOutput {
+    pub room_id: Uuid,
     pub message: MessageOutput,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomCreatedOutput {
+    pub room: RoomOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeftOutput {
+    pub room_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomsOutput {
+    pub rooms: Vec<RoomOutput>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessageOutput {
+    pub to: Uuid,
+    pub message: MessageOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryOutput {
+    pub room_id: Uuid,
+    pub messages: Vec<MessageOutput>,
+    pub cursor: Option<Uuid>,
+    /// Set on the oldest page if older messages were dropped by the retention
+    /// policy, to when the newest of them was posted.
+    pub pruned_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEditedOutput {
+    pub room_id: Uuid,
+    pub message: MessageOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageDeletedOutput {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionsChangedOutput {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    /// Every reaction the message now has, ordered by emoji.
+    pub reactions: Vec<ReactionOutput>,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepliesChangedOutput {
+    pub room_id: Uuid,
+    /// First message of the thread.
+    pub message_id: Uuid,
+    pub reply_count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadOutput {
+    pub room_id: Uuid,
+    pub root_id: Uuid,
+    /// First message of the thread, unless it was already dropped from
+    /// history.
+    pub root: Option<MessageOutput>,
+    /// Every kept reply, oldest first.
+    pub replies: Vec<MessageOutput>,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserTypingOutput {
+    pub user_id: Uuid,
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModeratedOutput {
+    pub action: ModerationAction,
+    pub moderator: UserOutput,
+    pub users: Vec<UserOutput>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HelloOutput {
+    /// Version the server speaks with this client from now on.
+    pub version: u32,
+    /// Everything the server can offer, whether or not the client asked.
+    pub capabilities: Vec<Capability>,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaughtUpOutput {
+    /// Sequence number of the last output replayed, or of the last one sent
+    /// if there was nothing to replay.
+    pub last_seq: u64,
+}
+
 impl UserOutput {
     pub fn new(id: Uuid, name: &str) -> Self {
         UserOutput {
@@ -136,47 +744,242 @@ impl UserOutput {
     }
 }
 
+impl RoomOutput {
+    pub fn new(id: Uuid, name: &str) -> Self {
+        RoomOutput {
+            id,
+            name: String::from(name),
+        }
+    }
+}
+
 impl MessageOutput {
-    pub fn new(id: Uuid, user: UserOutput, body: &str, created_at: DateTime<Utc>) -> Self {
+    pub fn new(
+        id: Uuid,
+        user: UserOutput,
+        body: &str,
+        created_at: DateTime<Utc>,
+        edited_at: Option<DateTime<Utc>>,
+        deleted: bool,
+        reactions: Vec<ReactionOutput>,
+    ) -> Self {
         MessageOutput {
             id,
             user,
             body: String::from(body),
             created_at,
+            edited_at,
+            deleted,
+            reactions,
+            reply_to: None,
+            thread_root: None,
+            reply_count: 0,
+        }
+    }
+
+    pub fn with_thread(
+        mut self,
+        reply_to: Option<Uuid>,
+        thread_root: Option<Uuid>,
+        reply_count: usize,
+    ) -> Self {
+        self.reply_to = reply_to;
+        self.thread_root = thread_root;
+        self.reply_count = reply_count;
+        self
+    }
+}
+
+impl ReactionOutput {
+    pub fn new(emoji: &str, user_ids: Vec<Uuid>) -> Self {
+        ReactionOutput {
+            emoji: String::from(emoji),
+            count: user_ids.len(),
+            user_ids,
         }
     }
 }
 
 impl JoinedOutput {
-    pub fn new(user: UserOutput, others: Vec<UserOutput>, messages: Vec<MessageOutput>) -> Self {
+    pub fn new(
+        room: RoomOutput,
+        user: UserOutput,
+        others: Vec<UserOutput>,
+        messages: Vec<MessageOutput>,
+        cursor: Option<Uuid>,
+        pruned_until: Option<DateTime<Utc>>,
+        session_token: Option<String>,
+    ) -> Self {
         JoinedOutput {
+            room,
             user,
             others,
             messages,
+            cursor,
+            pruned_until,
+            session_token,
         }
     }
 }
 
+impl ResumedOutput {
+    pub fn new(user: UserOutput, rooms: Vec<RoomOutput>) -> Self {
+        ResumedOutput { user, rooms }
+    }
+}
+
 impl UserJoinedOutput {
-    pub fn new(user: UserOutput) -> Self {
-        UserJoinedOutput { user }
+    pub fn new(room_id: Uuid, user: UserOutput) -> Self {
+        UserJoinedOutput { room_id, user }
     }
 }
 
 impl UserLeftOutput {
-    pub fn new(user_id: Uuid) -> Self {
-        UserLeftOutput { user_id }
+    pub fn new(room_id: Uuid, user_id: Uuid) -> Self {
+        UserLeftOutput { room_id, user_id }
     }
 }
 
 impl PostedOutput {
-    pub fn new(message: MessageOutput) -> Self {
-        PostedOutput { message }
+    pub fn new(room_id: Uuid, message: MessageOutput, nonce: Option<String>) -> Self {
+        PostedOutput {
+            room_id,
+            message,
+            nonce,
+        }
     }
 }
 
 impl UserPostedOutput {
-    pub fn new(message: MessageOutput) -> Self {
-        UserPostedOutput { message }
+    pub fn new(room_id: Uuid, message: MessageOutput) -> Self {
+        UserPostedOutput { room_id, message }
+    }
+}
+
+impl RoomCreatedOutput {
+    pub fn new(room: RoomOutput) -> Self {
+        RoomCreatedOutput { room }
+    }
+}
+
+impl LeftOutput {
+    pub fn new(room_id: Uuid) -> Self {
+        LeftOutput { room_id }
+    }
+}
+
+impl RoomsOutput {
+    pub fn new(rooms: Vec<RoomOutput>) -> Self {
+        RoomsOutput { rooms }
+    }
+}
+
+impl DirectMessageOutput {
+    pub fn new(to: Uuid, message: MessageOutput) -> Self {
+        DirectMessageOutput { to, message }
+    }
+}
+
+impl MessageEditedOutput {
+    pub fn new(room_id: Uuid, message: MessageOutput) -> Self {
+        MessageEditedOutput { room_id, message }
+    }
+}
+
+impl MessageDeletedOutput {
+    pub fn new(room_id: Uuid, message_id: Uuid) -> Self {
+        MessageDeletedOutput {
+            room_id,
+            message_id,
+        }
+    }
+}
+
+impl ReactionsChangedOutput {
+    pub fn new(room_id: Uuid, message_id: Uuid, reactions: Vec<ReactionOutput>) -> Self {
+        ReactionsChangedOutput {
+            room_id,
+            message_id,
+            reactions,
+        }
+    }
+}
+
+impl RepliesChangedOutput {
+    pub fn new(room_id: Uuid, message_id: Uuid, reply_count: usize) -> Self {
+        RepliesChangedOutput {
+            room_id,
+            message_id,
+            reply_count,
+        }
+    }
+}
+
+impl ThreadOutput {
+    pub fn new(
+        room_id: Uuid,
+        root_id: Uuid,
+        root: Option<MessageOutput>,
+        replies: Vec<MessageOutput>,
+    ) -> Self {
+        ThreadOutput {
+            room_id,
+            root_id,
+            root,
+            replies,
+        }
+    }
+}
+
+impl UserTypingOutput {
+    pub fn new(user_id: Uuid, active: bool) -> Self {
+        UserTypingOutput { user_id, active }
+    }
+}
+
+impl ModeratedOutput {
+    pub fn new(
+        action: ModerationAction,
+        moderator: UserOutput,
+        users: Vec<UserOutput>,
+        until: Option<DateTime<Utc>>,
+    ) -> Self {
+        ModeratedOutput {
+            action,
+            moderator,
+            users,
+            until,
+        }
+    }
+}
+
+impl HelloOutput {
+    pub fn new(version: u32, capabilities: Vec<Capability>) -> Self {
+        HelloOutput {
+            version,
+            capabilities,
+        }
+    }
+}
+
+impl CaughtUpOutput {
+    pub fn new(last_seq: u64) -> Self {
+        CaughtUpOutput { last_seq }
+    }
+}
+
+impl HistoryOutput {
+    pub fn new(
+        room_id: Uuid,
+        messages: Vec<MessageOutput>,
+        cursor: Option<Uuid>,
+        pruned_until: Option<DateTime<Utc>>,
+    ) -> Self {
+        HistoryOutput {
+            room_id,
+            messages,
+            cursor,
+            pruned_until,
+        }
     }
 }
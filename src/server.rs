@@ -1,61 +1,189 @@
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
 
-use futures::{StreamExt, TryStreamExt};
-use log::{error, info};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use log::{debug, error, info};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
+use warp::http::StatusCode;
 use warp::ws::WebSocket;
-use warp::Filter;
+use warp::{Filter, Reply};
 
-use crate::client::Client;
-use crate::hub::{Hub, HubOptions};
-use crate::proto::InputParcel;
+use crate::api::{self, ApiOptions, ServiceClient};
+use crate::client::{Client, Received};
+use crate::codec::Codec;
+use crate::error::Error;
+use crate::hub::{Health, Hub, HubOptions};
+use crate::proto::{Input, InputParcel, OutputError};
+use crate::storage::{MemoryStorage, Storage};
+use crate::tls::{self, CertResolver, RemoteAddr, TlsOptions};
 
+const DEFAULT_PORT: u16 = 8080;
 const MAX_FRAME_SIZE: usize = 1 << 16;
 
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub address: SocketAddr,
+    pub max_frame_size: usize,
+    /// Serve `wss://` instead of `ws://` when set.
+    pub tls: Option<TlsOptions>,
+    /// Allow posting over HTTP when set.
+    pub api: Option<ApiOptions>,
+}
+
 pub struct Server {
-    port: u16,
+    options: ServerOptions,
     hub: Arc<Hub>,
 }
 
 impl Server {
-    pub fn new(port: u16) -> Self {
+    pub fn new(options: ServerOptions, hub_options: HubOptions) -> Self {
+        Self::with_storage(options, hub_options, Box::new(MemoryStorage::new()))
+    }
+
+    pub fn with_storage(
+        options: ServerOptions,
+        hub_options: HubOptions,
+        storage: Box<dyn Storage>,
+    ) -> Self {
         Server {
-            port,
-            hub: Arc::new(Hub::new(HubOptions {
-                alive_interval: Some(Duration::from_secs(5)),
-            })),
+            options,
+            hub: Arc::new(Hub::with_storage(hub_options, storage)),
         }
     }
 
     pub async fn run(&self) {
-        println!("{:?}", MAX_FRAME_SIZE);
+        let max_frame_size = self.options.max_frame_size;
         let (input_sender, input_receiver) = mpsc::unbounded_channel::<InputParcel>();
         let hub = self.hub.clone();
+        let metrics_hub = self.hub.clone();
+        let health_hub = self.hub.clone();
+        let ready_hub = self.hub.clone();
+        let service = self.options.api.clone().map(|api_options| {
+            Arc::new(ServiceClient::new(
+                api_options,
+                self.hub.clone(),
+                input_sender.clone(),
+            ))
+        });
+        let api = api::routes(self.hub.clone(), service, max_frame_size as u64);
+
+        // Connections accepted over TLS carry their address in an extension
+        let remote_address = warp::addr::remote()
+            .and(warp::ext::optional::<RemoteAddr>())
+            .map(
+                |address: Option<SocketAddr>, tls_address: Option<RemoteAddr>| {
+                    tls_address.map(|RemoteAddr(address)| address).or(address)
+                },
+            );
 
         let feed = warp::path("feed")
             .and(warp::ws())
+            .and(warp::header::optional::<String>("sec-websocket-protocol"))
+            .and(remote_address)
             .and(warp::any().map(move || input_sender.clone()))
             .and(warp::any().map(move || hub.clone()))
             .map(
                 move |ws: warp::ws::Ws,
+                      protocols: Option<String>,
+                      address: Option<SocketAddr>,
                       input_sender: UnboundedSender<InputParcel>,
                       hub: Arc<Hub>| {
-                    ws.max_frame_size(MAX_FRAME_SIZE)
-                        .on_upgrade(move |web_socket| async move {
-                            tokio::spawn(Self::process_client(hub, web_socket, input_sender));
-                        })
+                    let negotiated = protocols.as_deref().and_then(Codec::negotiate);
+                    // Browsers fail the handshake if none of their subprotocols
+                    // is echoed back, so say which ones would do instead
+                    if protocols.is_some() && negotiated.is_none() {
+                        return warp::reply::with_status(
+                            format!(
+                                "Unsupported subprotocol, expected one of: {}",
+                                Codec::supported()
+                            ),
+                            StatusCode::BAD_REQUEST,
+                        )
+                        .into_response();
+                    }
+                    let codec = negotiated.unwrap_or_default();
+                    let reply = ws.max_frame_size(max_frame_size).on_upgrade(
+                        move |web_socket| async move {
+                            tokio::spawn(Self::process_client(
+                                hub,
+                                web_socket,
+                                codec,
+                                address,
+                                input_sender,
+                            ));
+                        },
+                    );
+                    // Clients that did not ask for a subprotocol get JSON without one
+                    match negotiated {
+                        Some(codec) => warp::reply::with_header(
+                            reply,
+                            "sec-websocket-protocol",
+                            codec.protocol(),
+                        )
+                        .into_response(),
+                        None => reply.into_response(),
+                    }
                 },
             );
 
+        let metrics = warp::path("metrics").and(warp::get()).map(move || {
+            warp::reply::with_header(
+                metrics_hub.metrics().encode(),
+                "content-type",
+                prometheus::TEXT_FORMAT,
+            )
+        });
+
+        let healthz = warp::path("healthz")
+            .and(warp::get())
+            .and(warp::any().map(move || health_hub.clone()))
+            .then(|hub: Arc<Hub>| async move {
+                let health = hub.health().await;
+                Self::health_reply(&health, health.is_live())
+            });
+
+        let readyz = warp::path("readyz")
+            .and(warp::get())
+            .and(warp::any().map(move || ready_hub.clone()))
+            .then(|hub: Arc<Hub>| async move {
+                let health = hub.health().await;
+                Self::health_reply(&health, health.is_ready())
+            });
+
+        let routes = feed.or(metrics).or(healthz).or(readyz).or(api);
+
         let shutdown = async {
             tokio::signal::ctrl_c()
                 .await
                 .expect("failed to install CTRL+C signal handler");
         };
-        let (_, serving) =
-            warp::serve(feed).bind_with_graceful_shutdown(([127, 0, 0, 1], self.port), shutdown);
+        let serving = async {
+            match &self.options.tls {
+                Some(tls_options) => {
+                    let resolver = Arc::new(
+                        CertResolver::new(tls_options.clone())
+                            .expect("failed to load TLS certificate"),
+                    );
+                    let listener = TcpListener::bind(self.options.address)
+                        .await
+                        .expect("failed to bind address");
+                    info!("Listening on {} with TLS", self.options.address);
+                    tokio::select! {
+                        _ = tls::serve(listener, resolver.clone(), warp::service(routes)) => {},
+                        _ = resolver.watch() => {},
+                        _ = shutdown => {},
+                    }
+                }
+                None => {
+                    let (address, serving) = warp::serve(routes)
+                        .bind_with_graceful_shutdown(self.options.address, shutdown);
+                    info!("Listening on {}", address);
+                    serving.await;
+                }
+            }
+        };
 
         let running_hub = self.hub.run(input_receiver);
 
@@ -65,41 +193,86 @@ impl Server {
         }
     }
 
+    fn health_reply(health: &Health, ok: bool) -> impl Reply {
+        let status = if ok {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        warp::reply::with_status(warp::reply::json(health), status)
+    }
+
     async fn process_client(
         hub: Arc<Hub>,
         web_socket: WebSocket,
+        codec: Codec,
+        address: Option<SocketAddr>,
         input_sender: UnboundedSender<InputParcel>,
     ) {
-        let output_receiver = hub.subscribe();
+        let client = Client::new(codec);
+        let outbox = hub.connect(client.id, address.map(|address| address.ip()));
+        // Clients turned away on connect only get to hear why
+        let turned_away = outbox.close_reason().is_some();
         let (ws_sink, ws_stream) = web_socket.split();
-        let client = Client::new();
 
         info!("Client {} connected", client.id);
 
-        let reading = client
-            .read_input(ws_stream)
-            .try_for_each(|input_parcel| async {
-                input_sender.send(input_parcel).unwrap();
-                Ok(())
-            });
+        let reading = async {
+            let received = client.read_input(ws_stream);
+            tokio::pin!(received);
+            while let Some(received) = received.try_next().await? {
+                match received {
+                    Received::Input(input_parcel) => {
+                        // Joins only reach the hub once the name is known to belong to the client
+                        if let Input::Join(input) = &input_parcel.input {
+                            if !hub.authenticate(client.id, input).await {
+                                continue;
+                            }
+                        }
+                        input_sender.send(input_parcel).unwrap();
+                    }
+                    Received::Malformed(err) => {
+                        debug!("Malformed input from client {}: {}", client.id, err);
+                        hub.reject(client.id, OutputError::MalformedInput);
+                    }
+                    Received::Closed(close_frame) => return Ok(close_frame),
+                }
+            }
+            Ok(None)
+        };
 
-        let (tx, rx) = mpsc::unbounded_channel();
-        tokio::spawn(rx.forward(ws_sink));
         let writing = client
-            .write_output(output_receiver.into_stream())
-            .try_for_each(|message| async {
-                tx.send(Ok(message)).unwrap();
-                Ok(())
-            });
+            .write_output(outbox)
+            .forward(ws_sink.sink_map_err(|err| Error::System(err.to_string())));
 
-        if let Err(err) = tokio::select! {
-            result = reading => result,
-            result = writing => result,
-        } {
-            error!("Client connection error: {}", err);
-        }
+        let result = if turned_away {
+            writing.await.map(|()| None)
+        } else {
+            tokio::select! {
+                result = reading => result,
+                result = writing => result.map(|()| None),
+            }
+        };
+        let close_frame = match result {
+            Ok(close_frame) => close_frame,
+            Err(err) => {
+                error!("Client connection error: {}", err);
+                None
+            }
+        };
 
-        hub.on_disconnect(client.id).await;
+        hub.on_disconnect(client.id, close_frame).await;
         info!("Client {} disconnected", client.id);
     }
 }
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            address: SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT)),
+            max_frame_size: MAX_FRAME_SIZE,
+            tls: None,
+            api: None,
+        }
+    }
+}
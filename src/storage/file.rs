@@ -0,0 +1,541 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::model::feed::FEED_CAPACITY;
+use crate::model::message::Message;
+use crate::model::room::Room;
+use crate::model::user::User;
+use crate::storage::{MemoryStorage, Retention, Storage};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Record {
+    #[serde(rename_all = "camelCase")]
+    Room { id: Uuid, name: String },
+    #[serde(rename_all = "camelCase")]
+    Message {
+        feed_id: Uuid,
+        id: Uuid,
+        user_id: Uuid,
+        user_name: String,
+        body: String,
+        created_at: DateTime<Utc>,
+        // Missing from logs written before threads existed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<Uuid>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thread_root: Option<Uuid>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Edit {
+        feed_id: Uuid,
+        id: Uuid,
+        body: String,
+        edited_at: DateTime<Utc>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Delete { feed_id: Uuid, id: Uuid },
+    #[serde(rename_all = "camelCase")]
+    React {
+        feed_id: Uuid,
+        id: Uuid,
+        user_id: Uuid,
+        emoji: String,
+        added: bool,
+    },
+    /// History of the feed up to `until` was pruned.
+    #[serde(rename_all = "camelCase")]
+    Prune { feed_id: Uuid, until: DateTime<Utc> },
+}
+
+/// Append-only log of JSON records, one per line.
+///
+/// The whole log is replayed into memory when opened, so reads never touch
+/// the disk. A trailing record that was only partially written (e.g. the
+/// process died mid-write) is dropped and truncated away during recovery, but
+/// any complete record that cannot be read fails the open, so that nothing
+/// after it is lost.
+///
+/// Pruning rewrites the log with only what is still kept, once it has grown to
+/// twice its size since the last rewrite.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    memory: MemoryStorage,
+    length: u64,
+    // Length right after the last rewrite, 0 to rewrite on the first prune
+    compacted_length: u64,
+}
+
+impl FileStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::with_feed_capacity(path, FEED_CAPACITY)
+    }
+
+    /// Keeps at most `feed_capacity` of the newest messages of each feed.
+    pub fn with_feed_capacity<P: AsRef<Path>>(path: P, feed_capacity: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let mut memory = MemoryStorage::with_feed_capacity(feed_capacity);
+        let mut length = 0;
+
+        if path.exists() {
+            let valid_length = Self::recover(path, &mut memory)?;
+            length = valid_length;
+            let file = OpenOptions::new().write(true).open(path)?;
+            if file.metadata()?.len() > valid_length {
+                warn!(
+                    "Truncating {} to last valid record at byte {}",
+                    path.display(),
+                    valid_length
+                );
+                file.set_len(valid_length)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileStorage {
+            path: path.to_path_buf(),
+            file,
+            memory,
+            length,
+            compacted_length: 0,
+        })
+    }
+
+    fn recover(path: &Path, memory: &mut MemoryStorage) -> Result<u64> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut valid_length = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            // Could be corruption or a record from a newer version, either way
+            // it is not ours to throw away
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => Self::apply(memory, record)?,
+                Err(err) => {
+                    return Err(Error::System(format!(
+                        "unreadable record at byte {} of {}: {}",
+                        valid_length,
+                        path.display(),
+                        err
+                    )))
+                }
+            }
+            valid_length += read as u64;
+        }
+        Ok(valid_length)
+    }
+
+    fn apply(memory: &mut MemoryStorage, record: Record) -> Result<()> {
+        match record {
+            Record::Room { id, name } => memory.add_room(&Room::new(id, &name)),
+            Record::Message {
+                feed_id,
+                id,
+                user_id,
+                user_name,
+                body,
+                created_at,
+                reply_to,
+                thread_root,
+            } => {
+                let mut message =
+                    Message::new(id, User::new(user_id, &user_name), &body, created_at);
+                if let (Some(reply_to), Some(thread_root)) = (reply_to, thread_root) {
+                    message = message.in_thread(reply_to, thread_root);
+                }
+                memory.add_message(feed_id, message)
+            }
+            Record::Edit {
+                feed_id,
+                id,
+                body,
+                edited_at,
+            } => memory.edit_message(feed_id, id, &body, edited_at),
+            Record::Delete { feed_id, id } => memory.delete_message(feed_id, id),
+            Record::React {
+                feed_id,
+                id,
+                user_id,
+                emoji,
+                added,
+            } => memory.react(feed_id, id, user_id, &emoji, added),
+            Record::Prune { feed_id, until } => {
+                memory.mark_pruned(feed_id, until);
+                Ok(())
+            }
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        let mut data = serde_json::to_vec(record)?;
+        data.push(b'\n');
+        self.file.write_all(&data)?;
+        self.file.sync_data()?;
+        self.length += data.len() as u64;
+        Ok(())
+    }
+
+    // Writes what is kept to a new log and swaps it in with a rename, so that
+    // a crash leaves either the old log or the new one
+    fn compact(&mut self) -> Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+        let file = File::create(&temp_path)?;
+        let mut writer = BufWriter::new(&file);
+        let mut write = |record: &Record| -> Result<()> {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+            Ok(())
+        };
+        for room in self.memory.rooms() {
+            write(&Record::Room {
+                id: room.id,
+                name: room.name,
+            })?;
+        }
+        for (feed_id, feed) in self.memory.feeds() {
+            if let Some(until) = feed.pruned_until() {
+                write(&Record::Prune { feed_id, until })?;
+            }
+            for message in feed.iter() {
+                for record in message_records(feed_id, message) {
+                    write(&record)?;
+                }
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.length = self.file.metadata()?.len();
+        self.compacted_length = self.length;
+        Ok(())
+    }
+}
+
+// Records that restore the message as it is now
+fn message_records(feed_id: Uuid, message: &Message) -> Vec<Record> {
+    let mut records = vec![Record::Message {
+        feed_id,
+        id: message.id,
+        user_id: message.user.id,
+        user_name: message.user.name.clone(),
+        body: message.body.clone(),
+        created_at: message.created_at,
+        reply_to: message.reply_to,
+        thread_root: message.thread_root,
+    }];
+    if let Some(edited_at) = message.edited_at {
+        records.push(Record::Edit {
+            feed_id,
+            id: message.id,
+            body: message.body.clone(),
+            edited_at,
+        });
+    }
+    if message.deleted {
+        records.push(Record::Delete {
+            feed_id,
+            id: message.id,
+        });
+    }
+    for (user_id, emojis) in &message.reactions {
+        for emoji in emojis {
+            records.push(Record::React {
+                feed_id,
+                id: message.id,
+                user_id: *user_id,
+                emoji: emoji.clone(),
+                added: true,
+            });
+        }
+    }
+    records
+}
+
+impl Storage for FileStorage {
+    fn add_room(&mut self, room: &Room) -> Result<()> {
+        self.append(&Record::Room {
+            id: room.id,
+            name: room.name.clone(),
+        })?;
+        self.memory.add_room(room)
+    }
+
+    fn rooms(&self) -> Vec<Room> {
+        self.memory.rooms()
+    }
+
+    fn add_message(&mut self, feed_id: Uuid, message: Message) -> Result<()> {
+        for record in message_records(feed_id, &message) {
+            self.append(&record)?;
+        }
+        self.memory.add_message(feed_id, message)
+    }
+
+    fn edit_message(
+        &mut self,
+        feed_id: Uuid,
+        message_id: Uuid,
+        body: &str,
+        edited_at: DateTime<Utc>,
+    ) -> Result<()> {
+        self.append(&Record::Edit {
+            feed_id,
+            id: message_id,
+            body: String::from(body),
+            edited_at,
+        })?;
+        self.memory
+            .edit_message(feed_id, message_id, body, edited_at)
+    }
+
+    fn delete_message(&mut self, feed_id: Uuid, message_id: Uuid) -> Result<()> {
+        self.append(&Record::Delete {
+            feed_id,
+            id: message_id,
+        })?;
+        self.memory.delete_message(feed_id, message_id)
+    }
+
+    fn react(
+        &mut self,
+        feed_id: Uuid,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: &str,
+        added: bool,
+    ) -> Result<()> {
+        self.append(&Record::React {
+            feed_id,
+            id: message_id,
+            user_id,
+            emoji: String::from(emoji),
+            added,
+        })?;
+        self.memory
+            .react(feed_id, message_id, user_id, emoji, added)
+    }
+
+    fn message(&self, feed_id: Uuid, message_id: Uuid) -> Option<Message> {
+        self.memory.message(feed_id, message_id)
+    }
+
+    fn messages(&self, feed_id: Uuid, before: Option<Uuid>, limit: usize) -> Vec<Message> {
+        self.memory.messages(feed_id, before, limit)
+    }
+
+    fn replies(&self, feed_id: Uuid, root_id: Uuid) -> Vec<Message> {
+        self.memory.replies(feed_id, root_id)
+    }
+
+    fn pruned_until(&self, feed_id: Uuid) -> Option<DateTime<Utc>> {
+        self.memory.pruned_until(feed_id)
+    }
+
+    fn prune(&mut self, retention: &Retention, now: DateTime<Utc>) -> usize {
+        let pruned = self.memory.prune(retention, now);
+        if pruned > 0 && self.length >= self.compacted_length * 2 {
+            if let Err(err) = self.compact() {
+                warn!("Failed to compact {}: {}", self.path.display(), err);
+            }
+        }
+        pruned
+    }
+
+    fn check(&self) -> Result<()> {
+        // Appending to a log that was moved or deleted would silently lose records
+        if !self.path.exists() {
+            return Err(Error::System(format!(
+                "{} no longer exists",
+                self.path.display()
+            )));
+        }
+        self.file.metadata()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use crate::model::message::Message;
+    use crate::model::room::Room;
+    use crate::model::user::User;
+    use crate::storage::{FileStorage, Retention, Storage};
+
+    #[test]
+    fn recover_after_reopen() {
+        let path = env::temp_dir().join(format!("rusty-chat-{}.log", Uuid::new_v4()));
+        let room = Room::new(Uuid::new_v4(), "Random");
+        let user = User::new(Uuid::new_v4(), "John");
+
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage.add_room(&room).unwrap();
+            let mut ids = Vec::new();
+            for body in &["Hello", "Wrld", "Oops"] {
+                let message = Message::new(Uuid::new_v4(), user.clone(), body, Utc::now());
+                ids.push(message.id);
+                storage.add_message(room.id, message).unwrap();
+            }
+            storage
+                .edit_message(room.id, ids[1], "World", Utc::now())
+                .unwrap();
+            storage.delete_message(room.id, ids[2]).unwrap();
+            for (emoji, added) in &[("👍", true), ("🎉", true), ("🎉", false)] {
+                storage
+                    .react(room.id, ids[0], user.id, emoji, *added)
+                    .unwrap();
+            }
+        }
+
+        // Simulate a crash in the middle of writing a record
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"type\":\"message\",\"feedId\"")
+            .unwrap();
+
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            let rooms = storage.rooms();
+            assert_eq!(rooms.len(), 1);
+            assert_eq!(rooms[0].name, "Random");
+            let bodies: Vec<String> = storage
+                .messages(room.id, None, 10)
+                .into_iter()
+                .map(|message| message.body)
+                .collect();
+            assert_eq!(bodies, vec!["Hello", "World", ""]);
+
+            // Appending after recovery must not be glued to the partial record
+            let root_id = storage.messages(room.id, None, 10)[0].id;
+            storage
+                .add_message(
+                    room.id,
+                    Message::new(Uuid::new_v4(), user.clone(), "Again", Utc::now())
+                        .in_thread(root_id, root_id),
+                )
+                .unwrap();
+        }
+
+        let storage = FileStorage::open(&path).unwrap();
+        let messages = storage.messages(room.id, None, 10);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].reply_count, 1);
+        let replies = storage.replies(room.id, messages[0].id);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].body, "Again");
+        assert_eq!(replies[0].reply_to, Some(messages[0].id));
+        assert!(messages[1].edited_at.is_some());
+        assert!(messages[2].deleted);
+        assert_eq!(
+            messages[0]
+                .reactions_by_emoji()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![("👍", vec![user.id])]
+        );
+        assert!(storage.check().is_ok());
+
+        // A removed log can no longer be written to
+        fs::remove_file(&path).unwrap();
+        assert!(storage.check().is_err());
+    }
+
+    #[test]
+    fn refuse_corrupt_record() {
+        let path = env::temp_dir().join(format!("rusty-chat-{}.log", Uuid::new_v4()));
+        let room = Room::new(Uuid::new_v4(), "Random");
+        let user = User::new(Uuid::new_v4(), "John");
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage.add_room(&room).unwrap();
+            storage
+                .add_message(
+                    room.id,
+                    Message::new(Uuid::new_v4(), user, "Hello", Utc::now()),
+                )
+                .unwrap();
+        }
+
+        // A record this version does not know, with a good one after it
+        let contents = fs::read_to_string(&path).unwrap();
+        let (first, rest) = contents.split_at(contents.find('\n').unwrap() + 1);
+        let corrupted = format!("{}{{\"type\":\"unknown\"}}\n{}", first, rest);
+        fs::write(&path, &corrupted).unwrap();
+
+        assert!(FileStorage::open(&path).is_err());
+        // Nothing is truncated away
+        assert_eq!(fs::read_to_string(&path).unwrap(), corrupted);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_on_prune() {
+        let path = env::temp_dir().join(format!("rusty-chat-{}.log", Uuid::new_v4()));
+        let room = Room::new(Uuid::new_v4(), "Random");
+        let user = User::new(Uuid::new_v4(), "John");
+        let retention = Retention {
+            max_messages: Some(2),
+            max_age: None,
+            max_bytes: None,
+            interval: Duration::from_secs(60),
+        };
+
+        let length = {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage.add_room(&room).unwrap();
+            let mut ids = Vec::new();
+            for body in &["One", "Two", "Three", "Four"] {
+                let message = Message::new(Uuid::new_v4(), user.clone(), body, Utc::now());
+                ids.push(message.id);
+                storage.add_message(room.id, message).unwrap();
+            }
+            storage
+                .edit_message(room.id, ids[3], "Four!", Utc::now())
+                .unwrap();
+            storage.react(room.id, ids[3], user.id, "👍", true).unwrap();
+            let length = fs::metadata(&path).unwrap().len();
+            assert_eq!(storage.prune(&retention, Utc::now()), 2);
+            length
+        };
+
+        // Pruned messages are gone from the log too
+        assert!(fs::metadata(&path).unwrap().len() < length);
+        let storage = FileStorage::open(&path).unwrap();
+        assert_eq!(storage.rooms().len(), 1);
+        let messages = storage.messages(room.id, None, 10);
+        let bodies: Vec<&str> = messages
+            .iter()
+            .map(|message| message.body.as_str())
+            .collect();
+        assert_eq!(bodies, vec!["Three", "Four!"]);
+        assert!(messages[1].edited_at.is_some());
+        assert_eq!(messages[1].reactions_by_emoji().len(), 1);
+        assert!(storage.pruned_until(room.id).is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
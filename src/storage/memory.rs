@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::error::Result;
+use crate::model::feed::{Feed, FEED_CAPACITY};
+use crate::model::message::Message;
+use crate::model::room::Room;
+use crate::storage::{Retention, Storage};
+
+pub struct MemoryStorage {
+    rooms: HashMap<Uuid, String>,
+    feeds: HashMap<Uuid, Feed>,
+    feed_capacity: usize,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::with_feed_capacity(FEED_CAPACITY)
+    }
+
+    /// Keeps at most `feed_capacity` of the newest messages of each feed.
+    pub fn with_feed_capacity(feed_capacity: usize) -> Self {
+        MemoryStorage {
+            rooms: HashMap::new(),
+            feeds: HashMap::new(),
+            feed_capacity,
+        }
+    }
+
+    pub fn feeds(&self) -> impl Iterator<Item = (Uuid, &Feed)> {
+        self.feeds.iter().map(|(feed_id, feed)| (*feed_id, feed))
+    }
+
+    pub fn mark_pruned(&mut self, feed_id: Uuid, until: DateTime<Utc>) {
+        let feed_capacity = self.feed_capacity;
+        self.feeds
+            .entry(feed_id)
+            .or_insert_with(|| Feed::new(feed_capacity))
+            .mark_pruned(until);
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn add_room(&mut self, room: &Room) -> Result<()> {
+        self.rooms.insert(room.id, room.name.clone());
+        Ok(())
+    }
+
+    fn rooms(&self) -> Vec<Room> {
+        self.rooms
+            .iter()
+            .map(|(id, name)| Room::new(*id, name))
+            .collect()
+    }
+
+    fn add_message(&mut self, feed_id: Uuid, message: Message) -> Result<()> {
+        let feed_capacity = self.feed_capacity;
+        self.feeds
+            .entry(feed_id)
+            .or_insert_with(|| Feed::new(feed_capacity))
+            .add_message(message);
+        Ok(())
+    }
+
+    fn edit_message(
+        &mut self,
+        feed_id: Uuid,
+        message_id: Uuid,
+        body: &str,
+        edited_at: DateTime<Utc>,
+    ) -> Result<()> {
+        if let Some(feed) = self.feeds.get_mut(&feed_id) {
+            feed.edit_message(message_id, body, edited_at);
+        }
+        Ok(())
+    }
+
+    fn delete_message(&mut self, feed_id: Uuid, message_id: Uuid) -> Result<()> {
+        if let Some(feed) = self.feeds.get_mut(&feed_id) {
+            feed.delete_message(message_id);
+        }
+        Ok(())
+    }
+
+    fn react(
+        &mut self,
+        feed_id: Uuid,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: &str,
+        added: bool,
+    ) -> Result<()> {
+        if let Some(feed) = self.feeds.get_mut(&feed_id) {
+            feed.react(message_id, user_id, emoji, added);
+        }
+        Ok(())
+    }
+
+    fn message(&self, feed_id: Uuid, message_id: Uuid) -> Option<Message> {
+        self.feeds.get(&feed_id)?.message(message_id).cloned()
+    }
+
+    fn messages(&self, feed_id: Uuid, before: Option<Uuid>, limit: usize) -> Vec<Message> {
+        self.feeds
+            .get(&feed_id)
+            .map(|feed| feed.messages_before(before, limit).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn replies(&self, feed_id: Uuid, root_id: Uuid) -> Vec<Message> {
+        self.feeds
+            .get(&feed_id)
+            .map(|feed| feed.replies(root_id).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn pruned_until(&self, feed_id: Uuid) -> Option<DateTime<Utc>> {
+        self.feeds.get(&feed_id)?.pruned_until()
+    }
+
+    fn prune(&mut self, retention: &Retention, now: DateTime<Utc>) -> usize {
+        self.feeds
+            .values_mut()
+            .map(|feed| retention.prune(feed, now))
+            .sum()
+    }
+}
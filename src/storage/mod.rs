@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::error::Result;
+use crate::model::message::Message;
+use crate::model::room::Room;
+
+pub mod file;
+pub mod memory;
+pub mod retention;
+
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+pub use retention::Retention;
+
+/// Backend the hub keeps rooms and message history in.
+///
+/// Messages are grouped into feeds, identified by the id of the room (or
+/// direct conversation) they were posted to.
+pub trait Storage: Send + Sync {
+    fn add_room(&mut self, room: &Room) -> Result<()>;
+
+    fn rooms(&self) -> Vec<Room>;
+
+    fn add_message(&mut self, feed_id: Uuid, message: Message) -> Result<()>;
+
+    fn edit_message(
+        &mut self,
+        feed_id: Uuid,
+        message_id: Uuid,
+        body: &str,
+        edited_at: DateTime<Utc>,
+    ) -> Result<()>;
+
+    fn delete_message(&mut self, feed_id: Uuid, message_id: Uuid) -> Result<()>;
+
+    /// Adds the user's reaction to a message, or removes it if `added` is
+    /// false.
+    fn react(
+        &mut self,
+        feed_id: Uuid,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: &str,
+        added: bool,
+    ) -> Result<()>;
+
+    fn message(&self, feed_id: Uuid, message_id: Uuid) -> Option<Message>;
+
+    /// Returns up to `limit` of the newest messages posted before the message
+    /// with id `before` (or the newest overall), oldest first. Replies are
+    /// left out.
+    fn messages(&self, feed_id: Uuid, before: Option<Uuid>, limit: usize) -> Vec<Message>;
+
+    /// Returns every kept reply in the thread started by the message with id
+    /// `root_id`, oldest first.
+    fn replies(&self, feed_id: Uuid, root_id: Uuid) -> Vec<Message>;
+
+    /// Creation time of the newest message dropped from the feed, everything
+    /// posted up to then is gone for good.
+    fn pruned_until(&self, feed_id: Uuid) -> Option<DateTime<Utc>>;
+
+    /// Drops the oldest messages of every feed until each is within the
+    /// retention limits, returning how many were dropped.
+    fn prune(&mut self, retention: &Retention, now: DateTime<Utc>) -> usize;
+
+    /// Reports whether the backend can still take writes.
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
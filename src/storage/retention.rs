@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::model::feed::Feed;
+
+/// Limits on how much history each feed keeps, checked every `interval`.
+/// Messages past any of the limits are dropped, oldest first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    pub max_messages: Option<usize>,
+    pub max_age: Option<Duration>,
+    /// Largest total length of a feed's message bodies.
+    pub max_bytes: Option<usize>,
+    pub interval: Duration,
+}
+
+impl Retention {
+    /// Drops the oldest messages of the feed until it is within every limit,
+    /// returning how many were dropped.
+    pub fn prune(&self, feed: &mut Feed, now: DateTime<Utc>) -> usize {
+        let posted_after = self
+            .max_age
+            .and_then(|max_age| chrono::Duration::from_std(max_age).ok())
+            .map(|max_age| now - max_age);
+        let mut pruned = 0;
+        while let Some(oldest) = feed.oldest() {
+            let expired = self.max_messages.is_some_and(|max| feed.len() > max)
+                || self.max_bytes.is_some_and(|max| feed.bytes() > max)
+                || posted_after.is_some_and(|after| oldest.created_at <= after);
+            if !expired {
+                break;
+            }
+            feed.remove_oldest();
+            pruned += 1;
+        }
+        pruned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use crate::model::feed::Feed;
+    use crate::model::message::Message;
+    use crate::model::user::User;
+    use crate::storage::Retention;
+
+    #[test]
+    fn prune() {
+        let user = User::new(Uuid::new_v4(), "John");
+        let now = Utc::now();
+        let mut feed = Feed::default();
+        // One message an hour, the newest posted just now
+        for hours in (0..10).rev() {
+            feed.add_message(Message::new(
+                Uuid::new_v4(),
+                user.clone(),
+                "Hello",
+                now - chrono::Duration::hours(hours),
+            ));
+        }
+        let unlimited = Retention {
+            max_messages: None,
+            max_age: None,
+            max_bytes: None,
+            interval: Duration::from_secs(60),
+        };
+        assert_eq!(unlimited.prune(&mut feed, now), 0);
+        assert_eq!(feed.pruned_until(), None);
+
+        let retention = Retention {
+            max_messages: Some(8),
+            ..unlimited
+        };
+        assert_eq!(retention.prune(&mut feed, now), 2);
+        assert_eq!(feed.pruned_until(), Some(now - chrono::Duration::hours(8)));
+
+        let retention = Retention {
+            max_bytes: Some(30),
+            ..unlimited
+        };
+        assert_eq!(retention.prune(&mut feed, now), 2);
+        assert_eq!(feed.bytes(), 30);
+
+        let retention = Retention {
+            max_age: Some(Duration::from_secs(90 * 60)),
+            ..unlimited
+        };
+        assert_eq!(retention.prune(&mut feed, now), 4);
+        assert_eq!(feed.len(), 2);
+    }
+}
@@ -0,0 +1,265 @@
+use std::convert::Infallible;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use rustls_pemfile::Item;
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{self, Service};
+use warp::hyper::{Body, Request, Response};
+
+use crate::error::{Error, Result};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Paths to a PEM encoded certificate chain and its private key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsOptions {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Address of a client connected over TLS, which warp cannot see on its own.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+struct LoadedKey {
+    certified_key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+}
+
+/// Hands out the most recently loaded certificate, so that renewed
+/// certificates are served without a restart.
+pub struct CertResolver {
+    options: TlsOptions,
+    loaded: RwLock<LoadedKey>,
+}
+
+impl CertResolver {
+    pub fn new(options: TlsOptions) -> Result<Self> {
+        let loaded = LoadedKey {
+            modified: modified(&options),
+            certified_key: Arc::new(load_certified_key(&options)?),
+        };
+        Ok(CertResolver {
+            options,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// Reloads the certificate if either file changed since it was last
+    /// loaded, returning whether it did.
+    pub fn reload(&self) -> Result<bool> {
+        let modified = modified(&self.options);
+        if modified == self.loaded.read().unwrap().modified {
+            return Ok(false);
+        }
+        let certified_key = Arc::new(load_certified_key(&self.options)?);
+        *self.loaded.write().unwrap() = LoadedKey {
+            certified_key,
+            modified,
+        };
+        Ok(true)
+    }
+
+    pub async fn watch(&self) {
+        loop {
+            time::sleep(RELOAD_INTERVAL).await;
+            match self.reload() {
+                Ok(true) => info!("Reloaded TLS certificate"),
+                Ok(false) => {}
+                // Keep serving the old certificate, the files may be mid-renewal
+                Err(err) => error!("Failed to reload TLS certificate: {}", err),
+            }
+        }
+    }
+
+    fn certified_key(&self) -> Arc<CertifiedKey> {
+        self.loaded.read().unwrap().certified_key.clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key())
+    }
+}
+
+/// Accepts TLS connections and serves them with `service` until the listener
+/// fails for good.
+pub async fn serve<S>(listener: TcpListener, resolver: Arc<CertResolver>, service: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => {
+                backoff = MIN_ACCEPT_BACKOFF;
+                accepted
+            }
+            Err(err) => {
+                // Errors such as running out of file descriptors last a while,
+                // so wait instead of trying again straight away
+                error!("Failed to accept connection: {}", err);
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            // Clients that never finish the handshake do not get to hold on
+            // to the connection
+            let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    warn!("TLS handshake with {} failed: {}", address, err);
+                    return;
+                }
+                Err(_) => {
+                    warn!("TLS handshake with {} timed out", address);
+                    return;
+                }
+            };
+            let service = service::service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(RemoteAddr(address));
+                service.clone().call(request)
+            });
+            if let Err(err) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                warn!("Connection with {} failed: {}", address, err);
+            }
+        });
+    }
+}
+
+fn load_certified_key(options: &TlsOptions) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut open(&options.cert_path)?)?;
+    if certs.is_empty() {
+        return Err(Error::System(format!(
+            "no certificates found in {}",
+            options.cert_path.display()
+        )));
+    }
+
+    // Take the first private key in whichever format it was written
+    let mut reader = open(&options.key_path)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                break PrivateKey(key)
+            }
+            Some(_) => continue,
+            None => {
+                return Err(Error::System(format!(
+                    "no private key found in {}",
+                    options.key_path.display()
+                )))
+            }
+        }
+    };
+    let key = sign::any_supported_type(&key).map_err(|err| Error::System(err.to_string()))?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+// Latest change to either file
+fn modified(options: &TlsOptions) -> Option<SystemTime> {
+    let cert_modified = fs::metadata(&options.cert_path).ok()?.modified().ok()?;
+    let key_modified = fs::metadata(&options.key_path).ok()?.modified().ok()?;
+    Some(cert_modified.max(key_modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use uuid::Uuid;
+
+    use crate::tls::{CertResolver, TlsOptions};
+
+    fn write_cert(options: &TlsOptions, modified: SystemTime) {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        fs::write(&options.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&options.key_path, cert.serialize_private_key_pem()).unwrap();
+        for path in &[&options.cert_path, &options.key_path] {
+            set_modified(path, modified);
+        }
+    }
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn reload() {
+        let dir = env::temp_dir().join(format!("rusty-chat-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let options = TlsOptions {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        let start = SystemTime::now() - Duration::from_secs(60);
+        write_cert(&options, start);
+
+        let resolver = CertResolver::new(options.clone()).unwrap();
+        let first = resolver.certified_key();
+        assert!(!resolver.reload().unwrap());
+
+        // A broken renewal keeps the old certificate
+        fs::write(&options.key_path, "").unwrap();
+        set_modified(&options.key_path, start + Duration::from_secs(1));
+        assert!(resolver.reload().is_err());
+        assert_eq!(resolver.certified_key().cert, first.cert);
+
+        write_cert(&options, start + Duration::from_secs(2));
+        assert!(resolver.reload().unwrap());
+        assert_ne!(resolver.certified_key().cert, first.cert);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}